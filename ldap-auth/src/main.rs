@@ -1,80 +1,2465 @@
-use ldap3::{result::Result, LdapConn, LdapConnSettings, Scope, SearchEntry};
+use futures::stream::{self, StreamExt};
+use ldap3::{
+    adapters::PagedResults,
+    get_url_params, ldap_escape, parse_refs,
+    result::{LdapError, LdapResult, Result, SearchResult},
+    Ldap, LdapConn, LdapConnAsync, LdapConnSettings, ResultEntry, Scope, SearchEntry,
+};
 use native_tls::TlsConnector;
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::env;
+use std::fs;
+use std::hash::BuildHasher;
+use std::ops::{Deref, DerefMut};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{debug, instrument, warn};
+use tracing_subscriber::EnvFilter;
+use url::Url;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ConnectionMode {
+    // Unencrypted `ldap://`
+    Plain,
+    // Implicit TLS, expects an `ldaps://` URL
+    Ldaps,
+    // Plain `ldap://` connection upgraded with StartTLS before binding
+    StartTls,
+}
+
+// How a group DN is reported, `group_naming = "all_ous"` and so on in the config file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum GroupNaming {
+    // The first `ou=` below `ou=groups`, e.g. `admins`
+    FirstOu,
+    // Every `ou=` other than `ou=groups`, each reported as a group of its own
+    AllOus,
+    // The complete DN
+    FullDn,
+}
+
+// How to re-bind after the user bind, before searching for groups
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ServiceBind {
+    // Search on the user's own session; the directory must let users read their entry
+    None,
+    // Drop to an anonymous session, for directories that let anyone read memberships
+    Anonymous,
+    // Re-bind as a service account
+    Simple { dn: String, password: String },
+}
+
+impl ServiceBind {
+    // The DN and password to re-bind with, if any
+    fn credentials(&self) -> Option<(&str, &str)> {
+        match self {
+            ServiceBind::None => None,
+            ServiceBind::Anonymous => Some(("", "")),
+            ServiceBind::Simple { dn, password } => Some((dn, password)),
+        }
+    }
+}
+
+// What checking one user against one server came to
+#[derive(Debug)]
+enum AuthOutcome {
+    // The bind succeeded. `user_dn` is the DN of the entry the search found, which is more
+    // authoritative than the bind DN built from the template.
+    Ok {
+        user_dn: String,
+        groups: Vec<String>,
+        attributes: BTreeMap<String, Vec<String>>,
+    },
+    // The server rejected the password (invalidCredentials, rc 49)
+    BadCredentials,
+    // The bind DN doesn't exist (noSuchObject, rc 32)
+    NoSuchUser,
+    // Refused without contacting the server. A simple bind with an empty password is an
+    // unauthenticated bind (RFC 4513), which many servers accept for any name.
+    EmptyPassword,
+    // The server couldn't be reached, even after retrying
+    Unreachable(String),
+    // Anything else, from an unreachable server to a failed group search
+    ServerError(String),
+}
+
+impl AuthOutcome {
+    // Only for errors from the user bind. The same codes from the service account bind
+    // point at the configuration, not at the user.
+    fn from_bind_error(err: &LdapError) -> Self {
+        match err {
+            LdapError::LdapResult { result } if result.rc == 49 => AuthOutcome::BadCredentials,
+            LdapError::LdapResult { result } if result.rc == 32 => AuthOutcome::NoSuchUser,
+            err if is_transient(err) => AuthOutcome::Unreachable(describe_error(err)),
+            err => AuthOutcome::ServerError(describe_error(err)),
+        }
+    }
+
+    // Short machine-readable name for `--json`
+    fn kind(&self) -> &'static str {
+        match self {
+            AuthOutcome::Ok { .. } => "ok",
+            AuthOutcome::BadCredentials => "bad_credentials",
+            AuthOutcome::NoSuchUser => "no_such_user",
+            AuthOutcome::EmptyPassword => "empty_password",
+            AuthOutcome::Unreachable(_) => "unreachable",
+            AuthOutcome::ServerError(_) => "server_error",
+        }
+    }
+
+    fn error(&self) -> Option<String> {
+        match self {
+            AuthOutcome::Ok { .. } => None,
+            AuthOutcome::BadCredentials => Some("wrong password".to_string()),
+            AuthOutcome::NoSuchUser => Some("no such user".to_string()),
+            AuthOutcome::EmptyPassword => Some("empty password".to_string()),
+            AuthOutcome::Unreachable(message) | AuthOutcome::ServerError(message) => {
+                Some(message.clone())
+            }
+        }
+    }
+}
+
+// Successful checks remembered for `ttl`, keyed by the first server of the group and the
+// username. Only a hash of the password is kept, and a hit needs the same password, so a
+// wrong one still goes to the directory. A password changed on the server keeps working
+// from the cache until the entry expires.
+struct AuthCache {
+    ttl: Duration,
+    // Per-process random keys, the hashes mean nothing outside this run
+    hasher: RandomState,
+    entries: Mutex<HashMap<(String, String), CachedAuth>>,
+}
+
+struct CachedAuth {
+    password_hash: u64,
+    stored: Instant,
+    // Index of the server in its group that answered
+    server: usize,
+    user_dn: String,
+    groups: Vec<String>,
+    attributes: BTreeMap<String, Vec<String>>,
+}
+
+impl AuthCache {
+    fn new(ttl: Duration) -> Self {
+        AuthCache {
+            ttl,
+            hasher: RandomState::new(),
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn get(&self, group: &str, username: &str, password: &str) -> Option<(usize, AuthOutcome)> {
+        let mut entries = self.entries.lock().expect("cache lock poisoned");
+        let key = (group.to_string(), username.to_string());
+        let entry = entries.get(&key)?;
+        if entry.stored.elapsed() > self.ttl {
+            entries.remove(&key);
+            return None;
+        }
+        if entry.password_hash != self.hasher.hash_one(password) {
+            return None;
+        }
+        Some((
+            entry.server,
+            AuthOutcome::Ok {
+                user_dn: entry.user_dn.clone(),
+                groups: entry.groups.clone(),
+                attributes: entry.attributes.clone(),
+            },
+        ))
+    }
+
+    // Failed checks of any kind are never stored
+    fn insert(
+        &self,
+        group: &str,
+        username: &str,
+        password: &str,
+        server: usize,
+        outcome: &AuthOutcome,
+    ) {
+        let AuthOutcome::Ok {
+            user_dn,
+            groups,
+            attributes,
+        } = outcome
+        else {
+            return;
+        };
+        let entry = CachedAuth {
+            password_hash: self.hasher.hash_one(password),
+            stored: Instant::now(),
+            server,
+            user_dn: user_dn.clone(),
+            groups: groups.clone(),
+            attributes: attributes.clone(),
+        };
+        self.entries
+            .lock()
+            .expect("cache lock poisoned")
+            .insert((group.to_string(), username.to_string()), entry);
+    }
+
+    // Forget every entry, e.g. after changing a user's groups
+    fn clear_cache(&self) {
+        self.entries.lock().expect("cache lock poisoned").clear();
+    }
+}
+
+// One line of `--json --health-check` output
+#[derive(Serialize)]
+struct HealthResult<'a> {
+    server: &'a str,
+    up: bool,
+    naming_contexts: Vec<String>,
+    error: Option<String>,
+}
+
+// One line of `--json` output
+#[derive(Serialize)]
+struct CheckResult<'a> {
+    server: &'a str,
+    user: &'a str,
+    ok: bool,
+    outcome: &'a str,
+    dn: Option<String>,
+    groups: Vec<String>,
+    attributes: BTreeMap<String, Vec<String>>,
+    error: Option<String>,
+}
+
+// The settings of a check. The config file sets them under the field name unless renamed
+// below, and any field that isn't skipped becomes a config file key.
+#[derive(Deserialize)]
+#[serde(default)]
+struct LdapConfig {
+    // Base DN for searches, e.g. `dc=example,dc=org`
+    base_dn: String,
+    // Template for the user's bind DN, with `{username}` (or just `{}`) and `{base_dn}`
+    // placeholders, e.g. `uid={},ou=people,{base_dn}` or an AD style `{}@example.org`
+    #[serde(rename = "bind_template")]
+    bind_dn_template: String,
+    // Where to search for the user entry, defaults to `base_dn`
+    search_base: Option<String>,
+    // How far below `search_base` to look, e.g. `Scope::OneLevel` under a users OU
+    #[serde(rename = "scope", deserialize_with = "deserialize_scope")]
+    search_scope: Scope,
+    // Bind with every username verbatim, even ones that don't look like a DN. Without it
+    // only usernames that parse as a DN ending in `base_dn` are taken as one.
+    username_is_dn: bool,
+    // Who runs the group search after the user bind succeeded, from `service_dn` and
+    // `service_password` in the config file
+    #[serde(skip)]
+    service_bind: ServiceBind,
+    // Skip TLS certificate verification. Only meant for local testing against the
+    // self-signed glauth dev server on ldaps://localhost:3894.
+    allow_invalid_certs: bool,
+    // Upper bound for connecting and for each bind or search
+    #[serde(
+        rename = "operation_timeout_secs",
+        deserialize_with = "deserialize_secs"
+    )]
+    timeout: Duration,
+    // What to report for each group DN
+    group_naming: GroupNaming,
+    // Attribute on the user entry listing its groups, e.g. `memberOf` or `isMemberOf`
+    group_attr: String,
+    // Further attributes to fetch from the user entry in the same search, e.g. `mail`
+    // and `displayName`, reported by the name they were asked for
+    #[serde(rename = "attrs")]
+    user_attrs: Vec<String>,
+    // Subtree holding the group entries, defaults to `ou=groups` under `base_dn`
+    groups_dn: Option<String>,
+    // When the user entry lists no groups, look for groups whose `member` is the user
+    member_search_fallback: bool,
+    // When the user search only returns referrals, repeat it on the referred servers.
+    // Otherwise the check fails with a "referral received, not followed" error.
+    follow_referrals: bool,
+    // Entries per page for the paged searches
+    page_size: i32,
+    // How many levels of groups-of-groups to follow above the user's direct groups, 0 to
+    // only report direct memberships
+    #[serde(rename = "nested_groups")]
+    nested_group_depth: u32,
+    // Tries at connecting and binding before giving up on a server. Only failures to
+    // reach the server are retried, a rejected bind fails straight away.
+    connect_attempts: u32,
+    // Wait before the second attempt, doubled for every further one
+    #[serde(rename = "retry_backoff_ms", deserialize_with = "deserialize_millis")]
+    retry_backoff: Duration,
+    // Reuse successful checks of the same user for a while, off unless set, from
+    // `cache_ttl_secs` in the config file
+    #[serde(skip)]
+    cache: Option<AuthCache>,
+}
+
+impl Default for LdapConfig {
+    fn default() -> Self {
+        LdapConfig {
+            base_dn: "dc=example,dc=org".to_string(),
+            bind_dn_template: "{username},{base_dn}".to_string(),
+            search_base: None,
+            search_scope: Scope::Subtree,
+            username_is_dn: false,
+            service_bind: ServiceBind::None,
+            allow_invalid_certs: false,
+            timeout: Duration::from_secs(10),
+            group_naming: GroupNaming::FirstOu,
+            group_attr: "memberOf".to_string(),
+            user_attrs: Vec::new(),
+            groups_dn: None,
+            member_search_fallback: true,
+            follow_referrals: false,
+            page_size: 500,
+            nested_group_depth: 0,
+            connect_attempts: 3,
+            retry_backoff: Duration::from_millis(500),
+            cache: None,
+        }
+    }
+}
+
+impl LdapConfig {
+    // What the user search asks for: the group attribute plus any `user_attrs`
+    fn search_attrs(&self) -> Vec<&str> {
+        let mut attrs = vec![self.group_attr.as_str()];
+        attrs.extend(self.user_attrs.iter().map(String::as_str));
+        attrs
+    }
+
+    fn search_base(&self) -> &str {
+        self.search_base.as_deref().unwrap_or(&self.base_dn)
+    }
+
+    fn groups_dn(&self) -> String {
+        match &self.groups_dn {
+            Some(groups_dn) => groups_dn.clone(),
+            None => format!("ou=groups,{}", self.base_dn),
+        }
+    }
+
+    // Base, scope and filter of the search for the user entry. A full DN names the entry
+    // itself, wherever it lives, so only that one entry is read.
+    fn user_search<'a>(&'a self, username: &'a str) -> (&'a str, Scope, String) {
+        if self.is_full_dn(username) {
+            (username, Scope::Base, "(objectClass=*)".to_string())
+        } else {
+            (
+                self.search_base(),
+                self.search_scope,
+                format!("uid={}", escape_ldap_filter(username)),
+            )
+        }
+    }
+
+    // A username like `cn=svc,ou=services,dc=example,dc=org` is already a DN. One that
+    // doesn't end in `base_dn`, like glauth's `cn=johndoe,ou=admins`, is relative to it
+    // and still goes through the template.
+    fn is_full_dn(&self, username: &str) -> bool {
+        self.username_is_dn || dn_is_within(username, &self.base_dn)
+    }
+
+    fn bind_dn(&self, username: &str) -> String {
+        if self.is_full_dn(username) {
+            return username.to_string();
+        }
+        self.bind_dn_template
+            .replace("{username}", "{}")
+            .replace("{base_dn}", &self.base_dn)
+            // Last, so that braces in the username are left alone
+            .replace("{}", username)
+    }
+}
+
+// Settings read with `--config <path>`, all optional. Besides the `LdapConfig` fields it
+// holds what the CLI needs to set up the checks. Flags on the command line win over the
+// file, and the LDAP_SERVICE_* variables over its service account.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    servers: Vec<String>,
+    starttls: bool,
+    service_dn: Option<String>,
+    service_password: Option<String>,
+    // Upper bound for a whole check, as with --timeout
+    check_timeout_secs: Option<u64>,
+    cache_ttl_secs: Option<u64>,
+    concurrency: Option<usize>,
+    #[serde(flatten)]
+    ldap: LdapConfig,
+}
+
+// Reads and checks a `--config` file
+fn load_config_file(path: &str) -> std::result::Result<ConfigFile, String> {
+    let contents = fs::read_to_string(path).map_err(|err| err.to_string())?;
+    parse_config_file(&contents)
+}
+
+// With the same rules as the matching flags
+fn parse_config_file(contents: &str) -> std::result::Result<ConfigFile, String> {
+    let file: ConfigFile = toml::from_str(contents).map_err(|err| err.to_string())?;
+
+    if let Some(url) = file
+        .servers
+        .iter()
+        .find(|url| !url.starts_with("ldap://") && !url.starts_with("ldaps://"))
+    {
+        return Err(format!(
+            "server '{}' is not an ldap:// or ldaps:// URL",
+            url
+        ));
+    }
+    let template = &file.ldap.bind_dn_template;
+    if !template.contains("{}") && !template.contains("{username}") {
+        return Err("bind_template needs a {} placeholder for the username".to_string());
+    }
+    if file.ldap.page_size <= 0 {
+        return Err("page_size needs to be a positive number".to_string());
+    }
+    if file.ldap.connect_attempts == 0 {
+        return Err("connect_attempts needs to be a positive number".to_string());
+    }
+    if file.concurrency == Some(0) {
+        return Err("concurrency needs to be greater than 0".to_string());
+    }
+    Ok(file)
+}
+
+fn deserialize_scope<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Scope, D::Error> {
+    let scope = String::deserialize(deserializer)?;
+    parse_scope(&scope).ok_or_else(|| D::Error::custom("scope needs to be one of base, one or sub"))
+}
+
+fn deserialize_secs<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Duration, D::Error> {
+    u64::deserialize(deserializer).map(Duration::from_secs)
+}
+
+fn deserialize_millis<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Duration, D::Error> {
+    u64::deserialize(deserializer).map(Duration::from_millis)
+}
+
+// An on/off setting: the last of `--<name>` and `--no-<name>` on the command line, or
+// else `current`, the config file's value or the default
+fn switch(flags: &[String], name: &str, current: bool) -> bool {
+    flags
+        .iter()
+        .rev()
+        .find_map(|flag| match flag.strip_prefix("--")?.strip_prefix("no-") {
+            Some(negated) if negated == name => Some(false),
+            Some(_) => None,
+            None if flag[2..] == *name => Some(true),
+            None => None,
+        })
+        .unwrap_or(current)
+}
+
+fn parse_scope(scope: &str) -> Option<Scope> {
+    match scope {
+        "base" => Some(Scope::Base),
+        "one" => Some(Scope::OneLevel),
+        "sub" => Some(Scope::Subtree),
+        _ => None,
+    }
+}
+
+// Flags without a value, so that a mistyped one is an error rather than ignored
+const FLAGS: &[&str] = &[
+    "--full-group-dns",
+    "--all-ous",
+    "--async",
+    "--search-as-user",
+    "--anonymous-search",
+    "--json",
+    "--fail-on-error",
+    "--health-check",
+];
+
+// Settings taken with `switch`, as `--<name>` or `--no-<name>`
+const SWITCHES: &[&str] = &[
+    "allow-invalid-certs",
+    "follow-referrals",
+    "member-search-fallback",
+    "username-is-dn",
+    "starttls",
+];
+
+fn is_switch(arg: &str) -> bool {
+    arg.strip_prefix("--")
+        .map(|name| name.strip_prefix("no-").unwrap_or(name))
+        .is_some_and(|name| SWITCHES.contains(&name))
+}
+
+// What is left of the command line once its settings went into the `ConfigFile`
+struct Invocation {
+    // Flags without a value, like `--json`
+    flags: Vec<String>,
+    // `<username>[:<password>]` arguments
+    args: Vec<String>,
+    from_file: Option<String>,
+}
+
+// Applies the command line on top of the config file's `settings`. Lists given as flags
+// replace the file's instead of adding to them.
+fn apply_args(
+    settings: &mut ConfigFile,
+    argv: Vec<String>,
+) -> std::result::Result<Invocation, String> {
+    let config = &mut settings.ldap;
+    let mut flags = Vec::new();
+    let mut args = Vec::new();
+    let mut from_file = None;
+    let mut server_urls = Vec::new();
+    let mut user_attrs = Vec::new();
+    let mut argv = argv.into_iter();
+    while let Some(arg) = argv.next() {
+        if arg == "--config" {
+            // Already loaded by the caller
+            argv.next();
+        } else if arg == "--from-file" {
+            from_file = Some(argv.next().ok_or("--from-file needs a path")?);
+        } else if arg == "--server" {
+            match argv.next() {
+                Some(url) if url.starts_with("ldap://") || url.starts_with("ldaps://") => {
+                    server_urls.push(url)
+                }
+                _ => return Err("--server needs an ldap:// or ldaps:// URL".to_string()),
+            }
+        } else if arg == "--bind-template" {
+            match argv.next() {
+                Some(template) if template.contains("{}") || template.contains("{username}") => {
+                    config.bind_dn_template = template
+                }
+                _ => {
+                    return Err(
+                        "--bind-template needs a template with a {} placeholder for the username"
+                            .to_string(),
+                    )
+                }
+            }
+        } else if arg == "--search-base" {
+            config.search_base = Some(argv.next().ok_or("--search-base needs a DN")?);
+        } else if arg == "--scope" {
+            config.search_scope = argv
+                .next()
+                .as_deref()
+                .and_then(parse_scope)
+                .ok_or("--scope needs one of base, one or sub")?;
+        } else if arg == "--attr" {
+            user_attrs.push(argv.next().ok_or("--attr needs an attribute name")?);
+        } else if arg == "--page-size" {
+            match argv.next().and_then(|size| size.parse().ok()) {
+                Some(size) if size > 0 => config.page_size = size,
+                _ => return Err("--page-size needs a positive number".to_string()),
+            }
+        } else if arg == "--nested-groups" {
+            config.nested_group_depth = argv
+                .next()
+                .and_then(|depth| depth.parse().ok())
+                .ok_or("--nested-groups needs a maximum depth")?;
+        } else if arg == "--connect-attempts" {
+            match argv.next().and_then(|attempts| attempts.parse().ok()) {
+                Some(attempts) if attempts > 0 => config.connect_attempts = attempts,
+                _ => return Err("--connect-attempts needs a positive number".to_string()),
+            }
+        } else if arg == "--retry-backoff-ms" {
+            config.retry_backoff = argv
+                .next()
+                .and_then(|ms| ms.parse().ok())
+                .map(Duration::from_millis)
+                .ok_or("--retry-backoff-ms needs a number of milliseconds")?;
+        } else if arg == "--concurrency" {
+            match argv.next().and_then(|n| n.parse().ok()) {
+                Some(n) if n > 0 => settings.concurrency = Some(n),
+                _ => {
+                    return Err("--concurrency needs a number of checks greater than 0".to_string())
+                }
+            }
+        } else if arg == "--timeout" {
+            settings.check_timeout_secs = Some(
+                argv.next()
+                    .and_then(|secs| secs.parse().ok())
+                    .ok_or("--timeout needs a number of seconds")?,
+            );
+        } else if arg == "--cache-ttl" {
+            settings.cache_ttl_secs = Some(
+                argv.next()
+                    .and_then(|secs| secs.parse().ok())
+                    .ok_or("--cache-ttl needs a number of seconds")?,
+            );
+        } else if FLAGS.contains(&arg.as_str()) || is_switch(&arg) {
+            flags.push(arg);
+        } else if arg.starts_with("--") {
+            return Err(format!("unknown option '{}'", arg));
+        } else {
+            args.push(arg);
+        }
+    }
+
+    if !server_urls.is_empty() {
+        settings.servers = server_urls;
+    }
+    if !user_attrs.is_empty() {
+        config.user_attrs = user_attrs;
+    }
+    config.allow_invalid_certs = switch(&flags, "allow-invalid-certs", config.allow_invalid_certs);
+    config.follow_referrals = switch(&flags, "follow-referrals", config.follow_referrals);
+    config.member_search_fallback = switch(
+        &flags,
+        "member-search-fallback",
+        config.member_search_fallback,
+    );
+    config.username_is_dn = switch(&flags, "username-is-dn", config.username_is_dn);
+    if flags.iter().any(|flag| flag == "--full-group-dns") {
+        config.group_naming = GroupNaming::FullDn;
+    } else if flags.iter().any(|flag| flag == "--all-ous") {
+        config.group_naming = GroupNaming::AllOus;
+    }
+    settings.starttls = switch(&flags, "starttls", settings.starttls);
+
+    Ok(Invocation {
+        flags,
+        args,
+        from_file,
+    })
+}
 
 fn main() -> Result<()> {
-    let args: Vec<String> = env::args().skip(1).collect();
+    // Logs go to stderr, filtered by RUST_LOG (e.g. RUST_LOG=ldap_auth=debug)
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .with_writer(std::io::stderr)
+        .init();
+
+    // The config file comes first, so the flags parsed below can override it
+    let all_args: Vec<String> = env::args().skip(1).collect();
+    let mut file = match all_args.iter().position(|arg| arg == "--config") {
+        Some(index) => {
+            let Some(path) = all_args.get(index + 1) else {
+                eprintln!("--config needs a path");
+                std::process::exit(1);
+            };
+            match load_config_file(path) {
+                Ok(file) => file,
+                Err(err) => {
+                    eprintln!("Could not read the config from '{}': {}", path, err);
+                    std::process::exit(1);
+                }
+            }
+        }
+        None => ConfigFile::default(),
+    };
+    let Invocation {
+        flags,
+        args,
+        from_file,
+    } = match apply_args(&mut file, all_args) {
+        Ok(invocation) => invocation,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    let ConfigFile {
+        servers: server_urls,
+        starttls,
+        service_dn,
+        service_password,
+        check_timeout_secs,
+        cache_ttl_secs,
+        concurrency,
+        ldap,
+    } = file;
+    let check_timeout = check_timeout_secs.map(Duration::from_secs);
+    let config = LdapConfig {
+        cache: cache_ttl_secs.map(|secs| AuthCache::new(Duration::from_secs(secs))),
+        ..ldap
+    };
 
-    if args.is_empty() {
-        println!("Usage: cargo run -- <username1>:<password1> <username2>:<password2>");
+    // Each check runs against one group of servers, trying them in order until one answers.
+    // Without --server every connection mode is checked on its own against the local
+    // dev server.
+    let server_groups: Vec<Vec<(String, String, ConnectionMode)>> = if server_urls.is_empty() {
+        [
+            ("ldap", "ldap://localhost:3893", ConnectionMode::Plain),
+            ("ldaps", "ldaps://localhost:3894", ConnectionMode::Ldaps),
+            (
+                "starttls",
+                "ldap://localhost:3893",
+                ConnectionMode::StartTls,
+            ),
+        ]
+        .into_iter()
+        .map(|(label, addr, mode)| vec![(label.to_string(), addr.to_string(), mode)])
+        .collect()
+    } else {
+        let failover = server_urls
+            .into_iter()
+            .map(|url| {
+                let mode = if url.starts_with("ldaps://") {
+                    ConnectionMode::Ldaps
+                } else if starttls {
+                    ConnectionMode::StartTls
+                } else {
+                    ConnectionMode::Plain
+                };
+                (url.clone(), url, mode)
+            })
+            .collect();
+        vec![failover]
+    };
+
+    // Readiness probe, which needs neither credentials nor a service account
+    if flags.iter().any(|flag| flag == "--health-check") {
+        let json = flags.iter().any(|flag| flag == "--json");
+        let mut all_up = true;
+        for (label, addr, mode) in server_groups.iter().flatten() {
+            let health = check_server_health(addr, *mode, &config);
+            all_up &= health.is_ok();
+            print_health(label, health, json);
+        }
+        std::process::exit(if all_up { 0 } else { 1 });
+    }
+
+    let service_bind = if flags.iter().any(|flag| flag == "--search-as-user") {
+        ServiceBind::None
+    } else if flags.iter().any(|flag| flag == "--anonymous-search") {
+        ServiceBind::Anonymous
+    } else {
+        match (
+            env::var("LDAP_SERVICE_DN").ok().or(service_dn),
+            env::var("LDAP_SERVICE_PASSWORD").ok().or(service_password),
+        ) {
+            (Some(dn), Some(password)) => ServiceBind::Simple { dn, password },
+            _ => {
+                eprintln!(
+                    "LDAP_SERVICE_DN and LDAP_SERVICE_PASSWORD (or service_dn and service_password in the config file) must be set to the service account used for the group search (or pass --search-as-user or --anonymous-search)"
+                );
+                std::process::exit(1);
+            }
+        }
+    };
+    let config = LdapConfig {
+        service_bind,
+        ..config
+    };
+    // Bounding or timing out checks needs them on the tokio runtime
+    let run_async = flags.iter().any(|flag| flag == "--async")
+        || concurrency.is_some()
+        || check_timeout.is_some();
+    let json = flags.iter().any(|flag| flag == "--json");
+
+    if args.is_empty() && from_file.is_none() {
+        println!(
+            "Usage: cargo run -- [--config <path>] [--[no-]allow-invalid-certs] [--full-group-dns] [--all-ous] [--[no-]member-search-fallback] [--[no-]follow-referrals] [--[no-]username-is-dn] [--async] [--search-as-user] [--anonymous-search] [--json] [--fail-on-error] [--health-check] [--from-file <path>] [--server <url>]... [--[no-]starttls] [--bind-template <template>] [--search-base <dn>] [--scope base|one|sub] [--attr <name>]... [--page-size <n>] [--nested-groups <depth>] [--connect-attempts <n>] [--retry-backoff-ms <ms>] [--cache-ttl <secs>] [--concurrency <n>] [--timeout <secs>] <username1>[:<password1>] <username2>[:<password2>]"
+        );
         return Ok(());
     }
 
-    for arg in args {
+    let mut credentials = Vec::new();
+    for arg in &args {
         if let Some((username, password)) = arg.split_once(':') {
-            // Try LDAP
-            match test_ldap_credentials("ldap://localhost:3893", username, password) {
-                Ok(groups) => println!("ldap : {} [OK] ({})", username, groups.join(", ")),
-                Err(err) => println!("ldap : {} [Failed: {}]", username, err),
+            credentials.push((username.to_string(), password.to_string()));
+        } else {
+            // Only a username, ask for the password without echoing it
+            match rpassword::prompt_password(format!("Password for {}: ", arg)) {
+                Ok(password) => credentials.push((arg.clone(), password)),
+                Err(err) => {
+                    eprintln!("Could not read the password for '{}': {}", arg, err);
+                    std::process::exit(1);
+                }
+            }
+        }
+    }
+    if let Some(path) = from_file {
+        match read_credentials_file(&path) {
+            Ok(mut from_file) => credentials.append(&mut from_file),
+            Err(err) => {
+                eprintln!("Could not read credentials from '{}': {}", path, err);
+                std::process::exit(1);
             }
+        }
+    }
 
-            // Try LDAPS
-            match test_ldap_credentials("ldaps://localhost:3894", username, password) {
-                Ok(groups) => println!("ldaps: {} [OK] ({})", username, groups.join(", ")),
-                Err(err) => println!("ldaps: {} [Failed: {}]", username, err),
+    let mut any_failed = false;
+    if run_async {
+        // Check every user against every server, up to `--concurrency` at a time and all at
+        // once without it. Results are printed in input order as soon as they are ready.
+        let runtime = tokio::runtime::Runtime::new()?;
+        let config = &config;
+        let server_groups = &server_groups;
+        let limit = concurrency.unwrap_or((credentials.len() * server_groups.len()).max(1));
+        let checks = credentials.iter().flat_map(|(username, password)| {
+            let (username, password) = (username.as_str(), password.as_str());
+            server_groups.iter().map(move |servers| async move {
+                let check = check_with_failover_async(servers, username, password, config);
+                let (label, outcome) = match check_timeout {
+                    Some(limit) => tokio::time::timeout(limit, check)
+                        .await
+                        .unwrap_or_else(|_| {
+                            let message = format!("check timed out after {}s", limit.as_secs());
+                            (servers[0].0.as_str(), AuthOutcome::Unreachable(message))
+                        }),
+                    None => check.await,
+                };
+                (label, username, outcome)
+            })
+        });
+        runtime.block_on(stream::iter(checks).buffered(limit).for_each(
+            |(label, username, outcome)| {
+                any_failed |= !matches!(outcome, AuthOutcome::Ok { .. });
+                print_result(label, username, outcome, json);
+                async {}
+            },
+        ));
+    } else {
+        for (username, password) in &credentials {
+            for servers in &server_groups {
+                let (label, outcome) = check_with_failover(servers, username, password, &config);
+                any_failed |= !matches!(outcome, AuthOutcome::Ok { .. });
+                print_result(label, username, outcome, json);
             }
+        }
+    }
+
+    // The password hashes are no use once the checks are done
+    if let Some(cache) = &config.cache {
+        cache.clear_cache();
+    }
+
+    // Exit codes: 0 when every check passed, or always without --fail-on-error. 1 when a
+    // check failed under --fail-on-error, and also for bad arguments or configuration.
+    if any_failed && flags.iter().any(|flag| flag == "--fail-on-error") {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+// Reads `username:password` pairs, one per line. Blank lines and `#` comments are skipped,
+// and whitespace around the colon is trimmed.
+fn read_credentials_file(path: &str) -> std::io::Result<Vec<(String, String)>> {
+    Ok(parse_credentials(&fs::read_to_string(path)?, path))
+}
+
+// Malformed lines are reported on stderr and skipped, so they stay out of `--json` output
+fn parse_credentials(contents: &str, path: &str) -> Vec<(String, String)> {
+    let mut credentials = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some((username, password)) = line.split_once(':') {
+            credentials.push((username.trim().to_string(), password.trim().to_string()));
         } else {
-            println!(
-                "Invalid argument format: '{}'. Expected format: <username>:<password>",
-                arg
+            eprintln!(
+                "Invalid line {} in '{}'. Expected format: <username>:<password>",
+                number + 1,
+                path
             );
         }
     }
+    credentials
+}
 
-    Ok(())
+fn print_result(label: &str, username: &str, outcome: AuthOutcome, json: bool) {
+    if json {
+        let error = outcome.error();
+        let kind = outcome.kind();
+        let (dn, groups, attributes) = match outcome {
+            AuthOutcome::Ok {
+                user_dn,
+                groups,
+                attributes,
+            } => (Some(user_dn), groups, attributes),
+            _ => (None, Vec::new(), BTreeMap::new()),
+        };
+        let line = CheckResult {
+            server: label,
+            user: username,
+            ok: error.is_none(),
+            outcome: kind,
+            dn,
+            groups,
+            attributes,
+            error,
+        };
+        println!(
+            "{}",
+            serde_json::to_string(&line).expect("check results always serialize")
+        );
+        return;
+    }
+
+    match outcome {
+        AuthOutcome::Ok {
+            user_dn,
+            groups,
+            attributes,
+        } => println!(
+            "{:8}: {} [OK] {} ({}){}",
+            label,
+            username,
+            user_dn,
+            groups.join(", "),
+            attributes
+                .iter()
+                .map(|(name, values)| format!(" {}={}", name, values.join(";")))
+                .collect::<String>()
+        ),
+        failed => println!(
+            "{:8}: {} [Failed: {}]",
+            label,
+            username,
+            failed.error().unwrap_or_default()
+        ),
+    }
+}
+
+fn print_health(label: &str, health: Result<Vec<String>>, json: bool) {
+    if json {
+        let (naming_contexts, error) = match health {
+            Ok(naming_contexts) => (naming_contexts, None),
+            Err(err) => (Vec::new(), Some(describe_error(&err))),
+        };
+        let line = HealthResult {
+            server: label,
+            up: error.is_none(),
+            naming_contexts,
+            error,
+        };
+        println!(
+            "{}",
+            serde_json::to_string(&line).expect("health results always serialize")
+        );
+        return;
+    }
+
+    match health {
+        Ok(naming_contexts) => println!("{:8}: server up ({})", label, naming_contexts.join(", ")),
+        Err(err) => println!("{:8}: server down [{}]", label, describe_error(&err)),
+    }
+}
+
+fn describe_error(err: &LdapError) -> String {
+    match err {
+        LdapError::Timeout { .. } => "timed out".to_string(),
+        LdapError::LdapResult { result } if result.rc == 10 => format!(
+            "referral received, not followed ({})",
+            result.refs.join(", ")
+        ),
+        err => err.to_string(),
+    }
 }
 
-fn test_ldap_credentials(addr: &str, username: &str, password: &str) -> Result<Vec<String>> {
-    let mut ldap = if addr.starts_with("ldaps://") {
-        // LDAPS - Secure connection
+fn connection_settings(mode: ConnectionMode, config: &LdapConfig) -> Result<LdapConnSettings> {
+    let mut settings = LdapConnSettings::new()
+        .set_conn_timeout(config.timeout)
+        .set_starttls(mode == ConnectionMode::StartTls);
+    if mode != ConnectionMode::Plain && config.allow_invalid_certs {
+        // TLS without verifying the server certificate
         let tls_connector = TlsConnector::builder()
             .danger_accept_invalid_certs(true)
             .build()?;
-        LdapConn::with_settings(LdapConnSettings::new().set_connector(tls_connector), addr)?
-    } else {
-        // LDAP - Standard connection
-        LdapConn::with_settings(LdapConnSettings::new(), addr)?
+        settings = settings.set_connector(tls_connector);
+    }
+    Ok(settings)
+}
+
+// Connects, binds anonymously and reads the naming contexts from the root DSE. Returns
+// the naming contexts, which may be empty on servers that hide the root DSE.
+#[instrument(skip(config), err(level = "info"))]
+fn check_server_health(
+    addr: &str,
+    mode: ConnectionMode,
+    config: &LdapConfig,
+) -> Result<Vec<String>> {
+    let mut ldap = connect_and_bind(addr, mode, "", "", config)?;
+    let SearchResult(rs, res) = ldap.with_timeout(config.timeout).search(
+        "",
+        Scope::Base,
+        "(objectClass=*)",
+        vec!["namingContexts"],
+    )?;
+    res.success()?;
+
+    Ok(rs
+        .into_iter()
+        .map(SearchEntry::construct)
+        .filter_map(|mut entry| entry.attrs.remove("namingContexts"))
+        .flatten()
+        .collect())
+}
+
+// Checks against `servers` in order, moving on to the next only while they can't be
+// reached. Returns the label of the server that answered, or of the last one tried.
+fn check_with_failover<'a>(
+    servers: &'a [(String, String, ConnectionMode)],
+    username: &str,
+    password: &str,
+    config: &LdapConfig,
+) -> (&'a str, AuthOutcome) {
+    if let Some(hit) = cached_check(servers, username, password, config) {
+        return hit;
+    }
+    let mut last = None;
+    for (index, (label, addr, mode)) in servers.iter().enumerate() {
+        let outcome = test_ldap_credentials(addr, *mode, username, password, config);
+        if !matches!(outcome, AuthOutcome::Unreachable(_)) {
+            cache_check(servers, username, password, index, &outcome, config);
+            return (label, outcome);
+        }
+        debug!(%addr, "server unreachable, trying the next one");
+        last = Some((label.as_str(), outcome));
+    }
+    last.expect("server groups are never empty")
+}
+
+async fn check_with_failover_async<'a>(
+    servers: &'a [(String, String, ConnectionMode)],
+    username: &str,
+    password: &str,
+    config: &LdapConfig,
+) -> (&'a str, AuthOutcome) {
+    if let Some(hit) = cached_check(servers, username, password, config) {
+        return hit;
+    }
+    let mut last = None;
+    for (index, (label, addr, mode)) in servers.iter().enumerate() {
+        let outcome = test_ldap_credentials_async(addr, *mode, username, password, config).await;
+        if !matches!(outcome, AuthOutcome::Unreachable(_)) {
+            cache_check(servers, username, password, index, &outcome, config);
+            return (label, outcome);
+        }
+        debug!(%addr, "server unreachable, trying the next one");
+        last = Some((label.as_str(), outcome));
+    }
+    last.expect("server groups are never empty")
+}
+
+fn cached_check<'a>(
+    servers: &'a [(String, String, ConnectionMode)],
+    username: &str,
+    password: &str,
+    config: &LdapConfig,
+) -> Option<(&'a str, AuthOutcome)> {
+    let (server, outcome) = config
+        .cache
+        .as_ref()?
+        .get(&servers[0].0, username, password)?;
+    debug!(%username, "using the cached check");
+    Some((servers[server].0.as_str(), outcome))
+}
+
+fn cache_check(
+    servers: &[(String, String, ConnectionMode)],
+    username: &str,
+    password: &str,
+    server: usize,
+    outcome: &AuthOutcome,
+    config: &LdapConfig,
+) {
+    if let Some(cache) = &config.cache {
+        cache.insert(&servers[0].0, username, password, server, outcome);
+    }
+}
+
+// Failures to reach or talk to the server, which may go away on their own. Anything the
+// server actually answered, like a rejected bind, is final.
+fn is_transient(err: &LdapError) -> bool {
+    matches!(
+        err,
+        LdapError::Io { .. }
+            | LdapError::Timeout { .. }
+            | LdapError::OpSend { .. }
+            | LdapError::ResultRecv { .. }
+            | LdapError::EndOfStream
+    )
+}
+
+// A sync connection that unbinds when dropped, so it is closed on every return path
+// rather than whenever the socket happens to go away.
+struct BoundConn<C: Unbind = LdapConn>(C);
+
+// The one call `BoundConn` makes on drop, split out so tests can count it
+trait Unbind {
+    fn unbind(&mut self) -> Result<()>;
+}
+
+impl Unbind for LdapConn {
+    fn unbind(&mut self) -> Result<()> {
+        LdapConn::unbind(self)
+    }
+}
+
+impl<C: Unbind> Deref for BoundConn<C> {
+    type Target = C;
+
+    fn deref(&self) -> &C {
+        &self.0
+    }
+}
+
+impl<C: Unbind> DerefMut for BoundConn<C> {
+    fn deref_mut(&mut self) -> &mut C {
+        &mut self.0
+    }
+}
+
+impl<C: Unbind> Drop for BoundConn<C> {
+    fn drop(&mut self) {
+        if let Err(err) = self.0.unbind() {
+            debug!(%err, "unbind failed");
+        }
+    }
+}
+
+// Wraps `conn` before running `bind` on it, so a failed bind unbinds it as well
+fn bind_conn<C: Unbind>(conn: C, bind: impl FnOnce(&mut C) -> Result<()>) -> Result<BoundConn<C>> {
+    let mut conn = BoundConn(conn);
+    bind(&mut conn)?;
+    Ok(conn)
+}
+
+// Runs `try_once` until it succeeds, fails with an error that isn't transient, or has
+// been tried `connect_attempts` times, doubling the wait in between.
+fn with_retries<T>(config: &LdapConfig, mut try_once: impl FnMut() -> Result<T>) -> Result<T> {
+    let mut backoff = config.retry_backoff;
+    let mut attempt = 1;
+    loop {
+        match try_once() {
+            Err(err) if attempt < config.connect_attempts && is_transient(&err) => {
+                warn!(attempt, %err, ?backoff, "connection failed, retrying");
+                std::thread::sleep(backoff);
+                backoff *= 2;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+// Connects to `addr` and binds as `bind_dn`, retrying transient failures with backoff.
+fn connect_and_bind(
+    addr: &str,
+    mode: ConnectionMode,
+    bind_dn: &str,
+    password: &str,
+    config: &LdapConfig,
+) -> Result<BoundConn> {
+    with_retries(config, || {
+        let conn = LdapConn::with_settings(connection_settings(mode, config)?, addr)?;
+        bind_conn(conn, |ldap| {
+            ldap.with_timeout(config.timeout)
+                .simple_bind(bind_dn, password)?
+                .success()?;
+            Ok(())
+        })
+    })
+}
+
+async fn connect_and_bind_async(
+    addr: &str,
+    mode: ConnectionMode,
+    bind_dn: &str,
+    password: &str,
+    config: &LdapConfig,
+) -> Result<Ldap> {
+    let try_once = || async {
+        let (conn, mut ldap) =
+            LdapConnAsync::with_settings(connection_settings(mode, config)?, addr).await?;
+        ldap3::drive!(conn);
+        let bound = ldap
+            .with_timeout(config.timeout)
+            .simple_bind(bind_dn, password)
+            .await
+            .and_then(LdapResult::success);
+        if let Err(err) = bound {
+            unbind_async(&mut ldap).await;
+            return Err(err);
+        }
+        Ok::<_, LdapError>(ldap)
+    };
+
+    let mut backoff = config.retry_backoff;
+    let mut attempt = 1;
+    loop {
+        match try_once().await {
+            Err(err) if attempt < config.connect_attempts && is_transient(&err) => {
+                warn!(attempt, %err, ?backoff, "connection failed, retrying");
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[instrument(skip(password, config), ret(level = "info"))]
+fn test_ldap_credentials(
+    addr: &str,
+    mode: ConnectionMode,
+    username: &str,
+    password: &str,
+    config: &LdapConfig,
+) -> AuthOutcome {
+    if password.is_empty() {
+        return AuthOutcome::EmptyPassword;
+    }
+    let bind_dn = config.bind_dn(username);
+    debug!(%bind_dn, "binding as user");
+    let mut ldap = match connect_and_bind(addr, mode, &bind_dn, password, config) {
+        Ok(ldap) => ldap,
+        Err(err) => return AuthOutcome::from_bind_error(&err),
     };
 
-    let bind_dn = format!("{},dc=example,dc=org", username);
-    ldap.simple_bind(&bind_dn, password)?.success()?;
+    match find_user_groups(&mut ldap, mode, &bind_dn, username, password, config) {
+        Ok((user_dn, groups, attributes)) => AuthOutcome::Ok {
+            user_dn,
+            groups,
+            attributes,
+        },
+        Err(err) => AuthOutcome::ServerError(describe_error(&err)),
+    }
+}
 
-    // Search for groups with the service user
-    let bind_dn = format!("{},dc=example,dc=org", "serviceuser");
-    ldap.simple_bind(&bind_dn, "mysecret")?.success()?;
+// The user's DN, groups and requested attributes
+type UserEntry = (String, Vec<String>, BTreeMap<String, Vec<String>>);
+
+// Picks the `user_attrs` out of the user entry. Servers may return an attribute in a
+// different case than it was asked for, so names are matched ignoring case.
+fn requested_attrs(entry: &SearchEntry, config: &LdapConfig) -> BTreeMap<String, Vec<String>> {
+    config
+        .user_attrs
+        .iter()
+        .filter_map(|name| {
+            entry
+                .attrs
+                .iter()
+                .find(|(attr, _)| attr.eq_ignore_ascii_case(name))
+                .map(|(_, values)| (name.clone(), values.clone()))
+        })
+        .collect()
+}
+
+// The rest of a check once the user bind succeeded: the group search, following any
+// referrals and falling back to a member search. Returns the user's DN, groups and the
+// requested `user_attrs`.
+fn find_user_groups(
+    ldap: &mut LdapConn,
+    mode: ConnectionMode,
+    bind_dn: &str,
+    username: &str,
+    password: &str,
+    config: &LdapConfig,
+) -> Result<UserEntry> {
+    if let Some((service_dn, service_password)) = config.service_bind.credentials() {
+        debug!(%service_dn, "re-binding for the group search");
+        ldap.with_timeout(config.timeout)
+            .simple_bind(service_dn, service_password)?
+            .success()?;
+    }
 
     // Search for groups
-    let (rs, _res) = ldap
+    let (search_base, scope, filter) = config.user_search(username);
+    let (rs, res) = paged_search(
+        ldap.with_timeout(config.timeout),
+        search_base, // Base DN for the search
+        scope,
+        &filter,               // Search filter
+        config.search_attrs(), // Attributes to return (e.g., common name of the group)
+        config.page_size,
+    )?
+    .non_error()?;
+
+    let (mut entries, refs) = split_referrals(rs, &res);
+    // Referrals only matter if the user wasn't found locally
+    if entries.is_empty() && !refs.is_empty() {
+        if !config.follow_referrals {
+            return Err(referral_error(res, refs));
+        }
+        let (search_dn, search_password) = search_credentials(bind_dn, password, config);
+        for referral in &refs {
+            let (server, mode, base) = referral_target(referral, mode, config)?;
+            debug!(%server, ?mode, %base, "following referral");
+            let mut referred = connect_and_bind(&server, mode, search_dn, search_password, config)?;
+            let (rs, res) = paged_search(
+                referred.with_timeout(config.timeout),
+                &base,
+                scope,
+                &filter,
+                config.search_attrs(),
+                config.page_size,
+            )?
+            .success()?;
+            // Only one hop, further referrals are dropped
+            entries.extend(split_referrals(rs, &res).0);
+        }
+    }
+    let mut group_dns: Vec<String> = entries
+        .iter()
+        .filter_map(|entry| entry.attrs.get(&config.group_attr).cloned())
+        .flatten()
+        .collect();
+
+    // Without a matching entry the bind DN is the best we have
+    let user_dn = entries
+        .first()
+        .map_or_else(|| bind_dn.to_string(), |entry| entry.dn.clone());
+    let attributes = entries
+        .first()
+        .map(|entry| requested_attrs(entry, config))
+        .unwrap_or_default();
+    // Many directories don't populate memberOf, so fall back to asking the groups instead
+    if group_dns.is_empty() && config.member_search_fallback {
+        ldap.with_timeout(config.timeout);
+        group_dns = find_groups_by_member(ldap, &config.groups_dn(), &user_dn, config.page_size)?;
+    }
+    if config.nested_group_depth > 0 {
+        group_dns = resolve_nested_groups(ldap, group_dns, config)?;
+    }
+
+    Ok((user_dn, group_names(group_dns, config), attributes))
+}
+
+// Same checks as `test_ldap_credentials`, but on a tokio connection so many of them can
+// run concurrently. Must be called from within a tokio runtime.
+#[instrument(skip(password, config), ret(level = "info"))]
+async fn test_ldap_credentials_async(
+    addr: &str,
+    mode: ConnectionMode,
+    username: &str,
+    password: &str,
+    config: &LdapConfig,
+) -> AuthOutcome {
+    if password.is_empty() {
+        return AuthOutcome::EmptyPassword;
+    }
+    let bind_dn = config.bind_dn(username);
+    debug!(%bind_dn, "binding as user");
+    let mut ldap = match connect_and_bind_async(addr, mode, &bind_dn, password, config).await {
+        Ok(ldap) => ldap,
+        Err(err) => return AuthOutcome::from_bind_error(&err),
+    };
+
+    let groups =
+        find_user_groups_async(&mut ldap, mode, &bind_dn, username, password, config).await;
+    // Drop can't await, so async connections are unbound by hand on every path
+    unbind_async(&mut ldap).await;
+    match groups {
+        Ok((user_dn, groups, attributes)) => AuthOutcome::Ok {
+            user_dn,
+            groups,
+            attributes,
+        },
+        Err(err) => AuthOutcome::ServerError(describe_error(&err)),
+    }
+}
+
+async fn unbind_async(ldap: &mut Ldap) {
+    if let Err(err) = ldap.unbind().await {
+        debug!(%err, "unbind failed");
+    }
+}
+
+async fn find_user_groups_async(
+    ldap: &mut Ldap,
+    mode: ConnectionMode,
+    bind_dn: &str,
+    username: &str,
+    password: &str,
+    config: &LdapConfig,
+) -> Result<UserEntry> {
+    if let Some((service_dn, service_password)) = config.service_bind.credentials() {
+        debug!(%service_dn, "re-binding for the group search");
+        ldap.with_timeout(config.timeout)
+            .simple_bind(service_dn, service_password)
+            .await?
+            .success()?;
+    }
+
+    let (search_base, scope, filter) = config.user_search(username);
+    let (rs, res) = paged_search_async(
+        ldap.with_timeout(config.timeout),
+        search_base,
+        scope,
+        &filter,
+        config.search_attrs(),
+        config.page_size,
+    )
+    .await?
+    .non_error()?;
+
+    let (mut entries, refs) = split_referrals(rs, &res);
+    if entries.is_empty() && !refs.is_empty() {
+        if !config.follow_referrals {
+            return Err(referral_error(res, refs));
+        }
+        let (search_dn, search_password) = search_credentials(bind_dn, password, config);
+        for referral in &refs {
+            let (server, mode, base) = referral_target(referral, mode, config)?;
+            debug!(%server, ?mode, %base, "following referral");
+            let mut referred =
+                connect_and_bind_async(&server, mode, search_dn, search_password, config).await?;
+            let search = paged_search_async(
+                referred.with_timeout(config.timeout),
+                &base,
+                scope,
+                &filter,
+                config.search_attrs(),
+                config.page_size,
+            )
+            .await;
+            unbind_async(&mut referred).await;
+            let (rs, res) = search?.success()?;
+            entries.extend(split_referrals(rs, &res).0);
+        }
+    }
+    let mut group_dns: Vec<String> = entries
+        .iter()
+        .filter_map(|entry| entry.attrs.get(&config.group_attr).cloned())
+        .flatten()
+        .collect();
+
+    // Without a matching entry the bind DN is the best we have
+    let user_dn = entries
+        .first()
+        .map_or_else(|| bind_dn.to_string(), |entry| entry.dn.clone());
+    let attributes = entries
+        .first()
+        .map(|entry| requested_attrs(entry, config))
+        .unwrap_or_default();
+    if group_dns.is_empty() && config.member_search_fallback {
+        ldap.with_timeout(config.timeout);
+        group_dns =
+            find_groups_by_member_async(ldap, &config.groups_dn(), &user_dn, config.page_size)
+                .await?;
+    }
+    if config.nested_group_depth > 0 {
+        group_dns = resolve_nested_groups_async(ldap, group_dns, config).await?;
+    }
+
+    Ok((user_dn, group_names(group_dns, config), attributes))
+}
+
+// The DN and password the group search binds with
+fn search_credentials<'a>(
+    bind_dn: &'a str,
+    password: &'a str,
+    config: &'a LdapConfig,
+) -> (&'a str, &'a str) {
+    config
+        .service_bind
+        .credentials()
+        .unwrap_or((bind_dn, password))
+}
+
+// Separates the entries of a search from the referral URLs, both the search result
+// references and the referrals of an rc=10 result.
+fn split_referrals(rs: Vec<ResultEntry>, res: &LdapResult) -> (Vec<SearchEntry>, Vec<String>) {
+    let mut entries = Vec::new();
+    let mut refs = res.refs.clone();
+    for entry in rs {
+        if entry.is_ref() {
+            refs.extend(parse_refs(entry.0));
+        } else if !entry.is_intermediate() {
+            entries.push(SearchEntry::construct(entry));
+        }
+    }
+    (entries, refs)
+}
+
+fn referral_error(mut res: LdapResult, refs: Vec<String>) -> LdapError {
+    res.rc = 10;
+    res.refs = refs;
+    LdapError::LdapResult { result: res }
+}
+
+// Splits a referral URL like `ldap://dc2.example.org/ou=people,dc=example,dc=org` into
+// the server to connect to, how to connect and the base DN to search, defaulting to our
+// own base DN. The scheme picks the mode, except that an `ldap://` referral from an
+// encrypted connection is upgraded with StartTLS rather than sent in plain text.
+fn referral_target(
+    referral: &str,
+    mode: ConnectionMode,
+    config: &LdapConfig,
+) -> Result<(String, ConnectionMode, String)> {
+    let url = Url::parse(referral)?;
+    let mode = match (url.scheme(), mode) {
+        ("ldaps", _) => ConnectionMode::Ldaps,
+        ("ldap", ConnectionMode::Plain) => ConnectionMode::Plain,
+        ("ldap", _) => ConnectionMode::StartTls,
+        (scheme, _) => return Err(LdapError::UnknownScheme(scheme.to_string())),
+    };
+    let params = get_url_params(&url)?;
+    let base = if params.base.is_empty() {
+        config.base_dn.clone()
+    } else {
+        params.base.into_owned()
+    };
+    let server = match url.port() {
+        Some(port) => format!(
+            "{}://{}:{}",
+            url.scheme(),
+            url.host_str().unwrap_or(""),
+            port
+        ),
+        None => format!("{}://{}", url.scheme(), url.host_str().unwrap_or("")),
+    };
+    Ok((server, mode, base))
+}
+
+// Sorted and without duplicates, as several DNs can name the same group (e.g. a direct
+// and a transitive membership), and so that the output is stable between runs.
+fn group_names(group_dns: Vec<String>, config: &LdapConfig) -> Vec<String> {
+    let mut names: Vec<String> = group_dns
+        .into_iter()
+        .flat_map(|dn| match config.group_naming {
+            GroupNaming::FirstOu => parse_ou_from_dn(&dn).into_iter().collect(),
+            GroupNaming::AllOus => parse_ous_from_dn(&dn)
+                .into_iter()
+                .filter(|ou| !ou.eq_ignore_ascii_case("groups"))
+                .collect(),
+            GroupNaming::FullDn => vec![dn],
+        })
+        .collect();
+    names.sort();
+    names.dedup();
+    names
+}
+
+// Returns the DNs of the groups below `base_dn` that list `user_dn` as a `member`.
+fn find_groups_by_member(
+    ldap: &mut LdapConn,
+    base_dn: &str,
+    user_dn: &str,
+    page_size: i32,
+) -> Result<Vec<String>> {
+    let filter = format!("(member={})", escape_ldap_filter(user_dn));
+    // "1.1" requests no attributes, we only need the DNs
+    let (rs, res) = paged_search(
+        ldap,
+        base_dn,
+        Scope::Subtree,
+        &filter,
+        vec!["1.1"],
+        page_size,
+    )?
+    .non_error()?;
+
+    group_dns_from(rs, res)
+}
+
+async fn find_groups_by_member_async(
+    ldap: &mut Ldap,
+    base_dn: &str,
+    user_dn: &str,
+    page_size: i32,
+) -> Result<Vec<String>> {
+    let filter = format!("(member={})", escape_ldap_filter(user_dn));
+    let (rs, res) = paged_search_async(
+        ldap,
+        base_dn,
+        Scope::Subtree,
+        &filter,
+        vec!["1.1"],
+        page_size,
+    )
+    .await?
+    .non_error()?;
+
+    group_dns_from(rs, res)
+}
+
+// Adds the groups that the given groups are members of, level by level up to
+// `nested_group_depth`. DNs are compared case-insensitively, so membership cycles end.
+fn resolve_nested_groups(
+    ldap: &mut LdapConn,
+    group_dns: Vec<String>,
+    config: &LdapConfig,
+) -> Result<Vec<String>> {
+    let mut seen: HashSet<String> = group_dns.iter().map(|dn| dn.to_lowercase()).collect();
+    let mut all = group_dns.clone();
+    let mut frontier = group_dns;
+    for depth in 1..=config.nested_group_depth {
+        let mut next = Vec::new();
+        for group_dn in &frontier {
+            let mut parents = group_member_of(ldap, group_dn, config)?;
+            if parents.is_empty() && config.member_search_fallback {
+                ldap.with_timeout(config.timeout);
+                parents =
+                    find_groups_by_member(ldap, &config.groups_dn(), group_dn, config.page_size)?;
+            }
+            next.extend(
+                parents
+                    .into_iter()
+                    .filter(|parent| seen.insert(parent.to_lowercase())),
+            );
+        }
+        debug!(depth, found = next.len(), "resolved nested groups");
+        if next.is_empty() {
+            break;
+        }
+        all.extend(next.iter().cloned());
+        frontier = next;
+    }
+    Ok(all)
+}
+
+async fn resolve_nested_groups_async(
+    ldap: &mut Ldap,
+    group_dns: Vec<String>,
+    config: &LdapConfig,
+) -> Result<Vec<String>> {
+    let mut seen: HashSet<String> = group_dns.iter().map(|dn| dn.to_lowercase()).collect();
+    let mut all = group_dns.clone();
+    let mut frontier = group_dns;
+    for depth in 1..=config.nested_group_depth {
+        let mut next = Vec::new();
+        for group_dn in &frontier {
+            let mut parents = group_member_of_async(ldap, group_dn, config).await?;
+            if parents.is_empty() && config.member_search_fallback {
+                ldap.with_timeout(config.timeout);
+                parents = find_groups_by_member_async(
+                    ldap,
+                    &config.groups_dn(),
+                    group_dn,
+                    config.page_size,
+                )
+                .await?;
+            }
+            next.extend(
+                parents
+                    .into_iter()
+                    .filter(|parent| seen.insert(parent.to_lowercase())),
+            );
+        }
+        debug!(depth, found = next.len(), "resolved nested groups");
+        if next.is_empty() {
+            break;
+        }
+        all.extend(next.iter().cloned());
+        frontier = next;
+    }
+    Ok(all)
+}
+
+// The group attribute (e.g. `memberOf`) of the group entry itself. A group we can't see
+// has no parents as far as we are concerned.
+fn group_member_of(
+    ldap: &mut LdapConn,
+    group_dn: &str,
+    config: &LdapConfig,
+) -> Result<Vec<String>> {
+    let SearchResult(rs, res) = ldap.with_timeout(config.timeout).search(
+        group_dn,
+        Scope::Base,
+        "(objectClass=*)",
+        vec![config.group_attr.as_str()],
+    )?;
+    member_of_from(rs, res, config)
+}
+
+async fn group_member_of_async(
+    ldap: &mut Ldap,
+    group_dn: &str,
+    config: &LdapConfig,
+) -> Result<Vec<String>> {
+    let SearchResult(rs, res) = ldap
+        .with_timeout(config.timeout)
         .search(
-            "dc=example,dc=org",          // Base DN for the search
-            Scope::Subtree,               // Scope of the search
-            &format!("uid={}", username), // Search filter
-            vec!["memberOf"],             // Attributes to return (e.g., common name of the group)
-        )?
-        .success()?;
-
-    let groups: Vec<String> = rs
+            group_dn,
+            Scope::Base,
+            "(objectClass=*)",
+            vec![config.group_attr.as_str()],
+        )
+        .await?;
+    member_of_from(rs, res, config)
+}
+
+fn member_of_from(
+    rs: Vec<ResultEntry>,
+    res: LdapResult,
+    config: &LdapConfig,
+) -> Result<Vec<String>> {
+    // noSuchObject
+    if res.rc == 32 {
+        return Ok(Vec::new());
+    }
+    res.success()?;
+    Ok(rs
         .into_iter()
-        .filter_map(|entry| SearchEntry::construct(entry).attrs.get("memberOf").cloned())
+        .map(SearchEntry::construct)
+        .filter_map(|mut entry| entry.attrs.remove(&config.group_attr))
         .flatten()
-        .filter_map(|dn| parse_ou_from_dn(&dn))
-        .collect();
+        .collect())
+}
+
+// Search using the paged results control, so servers that cap the size of a single
+// response (e.g. AD at 1000 entries) still return everything.
+#[instrument(skip(ldap, attrs), err(level = "info"))]
+fn paged_search(
+    ldap: &mut LdapConn,
+    base: &str,
+    scope: Scope,
+    filter: &str,
+    attrs: Vec<&str>,
+    page_size: i32,
+) -> Result<SearchResult> {
+    let mut stream =
+        ldap.streaming_search_with(PagedResults::new(page_size), base, scope, filter, attrs)?;
+    let mut rs = Vec::new();
+    while let Some(entry) = stream.next()? {
+        rs.push(entry);
+    }
+    Ok(SearchResult(rs, stream.result()))
+}
+
+#[instrument(skip(ldap, attrs), err(level = "info"))]
+async fn paged_search_async(
+    ldap: &mut Ldap,
+    base: &str,
+    scope: Scope,
+    filter: &str,
+    attrs: Vec<&str>,
+    page_size: i32,
+) -> Result<SearchResult> {
+    let mut stream = ldap
+        .streaming_search_with(PagedResults::new(page_size), base, scope, filter, attrs)
+        .await?;
+    let mut rs = Vec::new();
+    while let Some(entry) = stream.next().await? {
+        rs.push(entry);
+    }
+    Ok(SearchResult(rs, stream.finish().await))
+}
+
+// Referrals aren't followed for the member search
+fn group_dns_from(rs: Vec<ResultEntry>, res: LdapResult) -> Result<Vec<String>> {
+    let (entries, refs) = split_referrals(rs, &res);
+    if entries.is_empty() && !refs.is_empty() {
+        return Err(referral_error(res, refs));
+    }
+    Ok(entries.into_iter().map(|entry| entry.dn).collect())
+}
+
+// Escape `*`, `(`, `)`, `\` and NUL in a filter value as required by RFC 4515, so user
+// input can't change the structure of a search filter.
+fn escape_ldap_filter(input: &str) -> String {
+    ldap_escape(input).into_owned()
+}
+
+// Splits a DN into (attribute, value) pairs, innermost first, following the string form
+// of RFC 4514: `\,` and `\2C` style escapes are decoded, attribute names are lowercased
+// and multi-valued RDNs (`cn=a+uid=b`) give one pair per value. A malformed DN gives an
+// empty vec.
+fn parse_dn(dn: &str) -> Vec<(String, String)> {
+    let mut pairs = Vec::new();
+    let mut attr: Option<String> = None;
+    let mut current = Vec::new();
+    // Length of `current` without trailing unescaped spaces, which aren't significant
+    let mut significant = 0;
+    let mut bytes = dn.bytes();
+
+    let mut finish = |attr: &mut Option<String>, current: &mut Vec<u8>, significant: usize| {
+        current.truncate(significant);
+        let value = String::from_utf8(std::mem::take(current)).ok()?;
+        let attr = attr.take().filter(|attr| !attr.is_empty())?;
+        pairs.push((attr, value));
+        Some(())
+    };
+
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'\\' => {
+                let escaped = match bytes.next() {
+                    Some(high) if high.is_ascii_hexdigit() => {
+                        let low = bytes.next().filter(u8::is_ascii_hexdigit);
+                        let Some(low) = low else { return Vec::new() };
+                        let hex = [high, low];
+                        let hex = std::str::from_utf8(&hex).expect("hex digits are ASCII");
+                        u8::from_str_radix(hex, 16).expect("two hex digits fit a byte")
+                    }
+                    Some(special) if b" \"#+,;<=>\\".contains(&special) => special,
+                    _ => return Vec::new(),
+                };
+                current.push(escaped);
+                significant = current.len();
+            }
+            b'=' if attr.is_none() => {
+                let name = String::from_utf8_lossy(&current)
+                    .trim()
+                    .to_ascii_lowercase();
+                attr = Some(name);
+                current.clear();
+                significant = 0;
+            }
+            b',' | b'+' => {
+                if finish(&mut attr, &mut current, significant).is_none() {
+                    return Vec::new();
+                }
+                significant = 0;
+            }
+            // Leading spaces aren't significant either
+            b' ' if current.is_empty() => {}
+            byte => {
+                current.push(byte);
+                if byte != b' ' {
+                    significant = current.len();
+                }
+            }
+        }
+    }
+    if !dn.is_empty() && finish(&mut attr, &mut current, significant).is_none() {
+        return Vec::new();
+    }
 
-    Ok(groups)
+    pairs
 }
 
+// Whether `dn` parses and ends in the RDNs of `base`, comparing names and values without
+// regard to case
+fn dn_is_within(dn: &str, base: &str) -> bool {
+    let dn = parse_dn(dn);
+    let base = parse_dn(base);
+    if dn.is_empty() || base.is_empty() || dn.len() < base.len() {
+        return false;
+    }
+    dn[dn.len() - base.len()..]
+        .iter()
+        .zip(&base)
+        .all(|((attr, value), (base_attr, base_value))| {
+            attr == base_attr && value.to_lowercase() == base_value.to_lowercase()
+        })
+}
+
+// Every `ou=` value in `dn`, innermost first
+fn parse_ous_from_dn(dn: &str) -> Vec<String> {
+    parse_dn(dn)
+        .into_iter()
+        .filter(|(attr, _)| attr == "ou")
+        .map(|(_, value)| value)
+        .collect()
+}
+
+// The innermost `ou=` in `dn` other than `ou=groups`
 fn parse_ou_from_dn(dn: &str) -> Option<String> {
-    dn.split(',')
-        .find(|component| component.starts_with("ou=") && !component.contains("ou=groups"))
-        .map(|ou_component| ou_component.replace("ou=", ""))
+    parse_ous_from_dn(dn)
+        .into_iter()
+        .find(|ou| !ou.eq_ignore_ascii_case("groups"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ldap3::asn1::{StructureTag, TagClass, PL};
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    // Stands in for an LdapConn, counting how often it was unbound
+    struct CountingConn(Rc<Cell<u32>>);
+
+    impl Unbind for CountingConn {
+        fn unbind(&mut self) -> Result<()> {
+            self.0.set(self.0.get() + 1);
+            Ok(())
+        }
+    }
+
+    fn ldap_result(rc: u32, text: &str) -> LdapError {
+        LdapError::LdapResult {
+            result: LdapResult {
+                rc,
+                matched: String::new(),
+                text: text.to_string(),
+                refs: Vec::new(),
+                ctrls: Vec::new(),
+            },
+        }
+    }
+
+    fn rejected_bind() -> LdapError {
+        ldap_result(49, "invalid credentials")
+    }
+
+    #[test]
+    fn relative_dn_usernames_get_the_base_dn_appended() {
+        let config = LdapConfig::default();
+        assert!(!config.is_full_dn("cn=johndoe,ou=admins"));
+        assert_eq!(
+            config.bind_dn("cn=johndoe,ou=admins"),
+            "cn=johndoe,ou=admins,dc=example,dc=org"
+        );
+        let (base, scope, filter) = config.user_search("cn=johndoe,ou=admins");
+        assert_eq!((base, scope), ("dc=example,dc=org", Scope::Subtree));
+        assert_eq!(filter, "uid=cn=johndoe,ou=admins");
+    }
+
+    #[test]
+    fn dn_usernames_under_the_base_dn_are_bound_verbatim() {
+        let config = LdapConfig::default();
+        for username in [
+            "cn=svc,ou=services,dc=example,dc=org",
+            "CN=svc, OU=services, DC=Example, DC=org",
+        ] {
+            assert!(config.is_full_dn(username), "{}", username);
+            assert_eq!(config.bind_dn(username), username);
+            assert_eq!(
+                config.user_search(username),
+                (username, Scope::Base, "(objectClass=*)".to_string())
+            );
+        }
+        // A different suffix, or a base DN merely contained in a value, doesn't count
+        assert!(!config.is_full_dn("cn=svc,dc=example,dc=com"));
+        assert!(!config.is_full_dn("cn=dc\\=example\\,dc\\=org"));
+    }
+
+    #[test]
+    fn username_is_dn_binds_anything_verbatim() {
+        let config = LdapConfig {
+            username_is_dn: true,
+            ..LdapConfig::default()
+        };
+        assert_eq!(
+            config.bind_dn("cn=johndoe,ou=admins"),
+            "cn=johndoe,ou=admins"
+        );
+        assert_eq!(config.bind_dn("johndoe"), "johndoe");
+    }
+
+    #[test]
+    fn groups_dn_defaults_to_ou_groups_under_the_base_dn() {
+        let config = LdapConfig {
+            base_dn: "dc=corp,dc=example,dc=com".to_string(),
+            ..LdapConfig::default()
+        };
+        assert_eq!(config.groups_dn(), "ou=groups,dc=corp,dc=example,dc=com");
+
+        let config = LdapConfig {
+            groups_dn: Some("ou=teams,dc=example,dc=org".to_string()),
+            ..config
+        };
+        assert_eq!(config.groups_dn(), "ou=teams,dc=example,dc=org");
+    }
+
+    #[test]
+    fn switches_take_the_last_flag_then_the_file() {
+        let flags =
+            |flags: &[&str]| -> Vec<String> { flags.iter().map(|flag| flag.to_string()).collect() };
+        assert!(!switch(&flags(&[]), "starttls", false));
+        assert!(switch(&flags(&[]), "starttls", true));
+        assert!(switch(&flags(&["--starttls"]), "starttls", false));
+        assert!(!switch(&flags(&["--no-starttls"]), "starttls", true));
+        assert!(switch(
+            &flags(&["--no-starttls", "--starttls"]),
+            "starttls",
+            false
+        ));
+        assert!(!switch(
+            &flags(&["--json", "--no-follow-referrals"]),
+            "follow-referrals",
+            true
+        ));
+        assert!(switch(&flags(&["--no-starttls"]), "follow-referrals", true));
+    }
+
+    #[test]
+    fn bound_conns_unbind_once_when_dropped() {
+        let unbinds = Rc::new(Cell::new(0));
+        let conn = bind_conn(CountingConn(unbinds.clone()), |_| Ok(())).unwrap();
+        assert_eq!(unbinds.get(), 0);
+        drop(conn);
+        assert_eq!(unbinds.get(), 1);
+    }
+
+    #[test]
+    fn failed_binds_unbind_straight_away() {
+        let unbinds = Rc::new(Cell::new(0));
+        let result = bind_conn(CountingConn(unbinds.clone()), |_| Err(rejected_bind()));
+        assert!(result.is_err());
+        assert_eq!(unbinds.get(), 1);
+    }
+
+    #[test]
+    fn every_retried_connection_is_unbound() {
+        let config = LdapConfig {
+            retry_backoff: Duration::ZERO,
+            ..LdapConfig::default()
+        };
+        let unbinds = Rc::new(Cell::new(0));
+
+        // Two transient failures, then a bind that works
+        let mut attempts = 0;
+        let conn = with_retries(&config, || {
+            attempts += 1;
+            bind_conn(CountingConn(unbinds.clone()), |_| match attempts {
+                1 | 2 => Err(LdapError::EndOfStream),
+                _ => Ok(()),
+            })
+        })
+        .unwrap();
+        assert_eq!((attempts, unbinds.get()), (3, 2));
+        drop(conn);
+        assert_eq!(unbinds.get(), 3);
+
+        // A rejected bind isn't retried
+        unbinds.set(0);
+        attempts = 0;
+        let result = with_retries(&config, || {
+            attempts += 1;
+            bind_conn(CountingConn(unbinds.clone()), |_| Err(rejected_bind()))
+        });
+        assert!(result.is_err());
+        assert_eq!((attempts, unbinds.get()), (1, 1));
+
+        // Nor is a transient failure past the last attempt
+        unbinds.set(0);
+        attempts = 0;
+        let result = with_retries(&config, || {
+            attempts += 1;
+            bind_conn(CountingConn(unbinds.clone()), |_| {
+                Err(LdapError::EndOfStream)
+            })
+        });
+        assert!(result.is_err());
+        assert_eq!((attempts, unbinds.get()), (3, 3));
+    }
+
+    #[test]
+    fn filter_values_escape_every_special_character() {
+        for (input, escaped) in [
+            ("*", r"\2a"),
+            ("(", r"\28"),
+            (")", r"\29"),
+            ("\\", r"\5c"),
+            ("\0", r"\00"),
+            ("jo*)(uid=*", r"jo\2a\29\28uid=\2a"),
+            ("johndoe", "johndoe"),
+        ] {
+            assert_eq!(escape_ldap_filter(input), escaped, "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn a_wildcard_username_searches_for_a_literal_star() {
+        let config = LdapConfig::default();
+        assert_eq!(config.user_search("*").2, r"uid=\2a");
+        assert_eq!(
+            config.user_search("*)(objectClass=*").2,
+            r"uid=\2a\29\28objectClass=\2a"
+        );
+    }
+
+    #[test]
+    fn parse_dn_follows_rfc_4514() {
+        let pairs = |pairs: &[(&str, &str)]| -> Vec<(String, String)> {
+            pairs
+                .iter()
+                .map(|(attr, value)| (attr.to_string(), value.to_string()))
+                .collect()
+        };
+        for (dn, expected) in [
+            (r"cn=a\,b,ou=x", pairs(&[("cn", "a,b"), ("ou", "x")])),
+            (r"cn=a\2Cb,ou=x", pairs(&[("cn", "a,b"), ("ou", "x")])),
+            (r"cn=a\2cb", pairs(&[("cn", "a,b")])),
+            // Only the escaped one of the trailing spaces is kept
+            (r"cn=a\  ,ou=x", pairs(&[("cn", "a "), ("ou", "x")])),
+            ("CN= a ,OU=x", pairs(&[("cn", "a"), ("ou", "x")])),
+            (
+                "cn=a+uid=b,ou=x",
+                pairs(&[("cn", "a"), ("uid", "b"), ("ou", "x")]),
+            ),
+            (
+                "cn=Jöns Åberg,ou=räksmörgås",
+                pairs(&[("cn", "Jöns Åberg"), ("ou", "räksmörgås")]),
+            ),
+            (r"cn=J\C3\B6ns", pairs(&[("cn", "Jöns")])),
+            // Malformed DNs give nothing at all
+            (r"cn=a\", Vec::new()),
+            (r"cn=a\2", Vec::new()),
+            (r"cn=a\x", Vec::new()),
+            ("cn=a,", Vec::new()),
+            ("cn=a+", Vec::new()),
+            ("cn=a,,ou=x", Vec::new()),
+            ("=a", Vec::new()),
+            ("johndoe", Vec::new()),
+            // Not UTF-8 once decoded
+            (r"cn=\FF", Vec::new()),
+            ("", Vec::new()),
+        ] {
+            assert_eq!(parse_dn(dn), expected, "{:?}", dn);
+        }
+    }
+
+    #[test]
+    fn ous_come_innermost_first_skipping_groups() {
+        let dn = r"cn=vpn,ou=it\, europe,ou=groups,dc=example,dc=org";
+        assert_eq!(parse_ous_from_dn(dn), ["it, europe", "groups"]);
+        assert_eq!(parse_ou_from_dn(dn).as_deref(), Some("it, europe"));
+        assert_eq!(parse_ou_from_dn("cn=vpn,ou=groups,dc=example,dc=org"), None);
+        assert!(parse_ous_from_dn(r"cn=vpn,ou=x\").is_empty());
+    }
+
+    #[test]
+    fn empty_passwords_are_refused_before_binding() {
+        // Nothing listens on port 1, so reaching the server would fail differently
+        let config = LdapConfig {
+            connect_attempts: 1,
+            ..LdapConfig::default()
+        };
+        let outcome = test_ldap_credentials(
+            "ldap://127.0.0.1:1",
+            ConnectionMode::Plain,
+            "johndoe",
+            "",
+            &config,
+        );
+        assert!(matches!(outcome, AuthOutcome::EmptyPassword));
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let outcome = runtime.block_on(test_ldap_credentials_async(
+            "ldap://127.0.0.1:1",
+            ConnectionMode::Plain,
+            "johndoe",
+            "",
+            &config,
+        ));
+        assert!(matches!(outcome, AuthOutcome::EmptyPassword));
+    }
+
+    fn ok_outcome() -> AuthOutcome {
+        AuthOutcome::Ok {
+            user_dn: "cn=johndoe,dc=example,dc=org".to_string(),
+            groups: vec!["admins".to_string()],
+            attributes: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn cached_checks_need_the_same_password() {
+        let cache = AuthCache::new(Duration::from_secs(60));
+        cache.insert("ldap", "johndoe", "secret", 1, &ok_outcome());
+        let (server, outcome) = cache.get("ldap", "johndoe", "secret").unwrap();
+        assert_eq!(server, 1);
+        assert!(matches!(outcome, AuthOutcome::Ok { groups, .. } if groups == ["admins"]));
+        assert!(cache.get("ldap", "johndoe", "wrong").is_none());
+        assert!(cache.get("ldaps", "johndoe", "secret").is_none());
+    }
+
+    #[test]
+    fn cached_checks_expire_after_the_ttl() {
+        let cache = AuthCache::new(Duration::from_millis(1));
+        cache.insert("ldap", "johndoe", "secret", 0, &ok_outcome());
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(cache.get("ldap", "johndoe", "secret").is_none());
+        assert!(cache.entries.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn failed_checks_are_never_cached() {
+        let cache = AuthCache::new(Duration::from_secs(60));
+        for outcome in [
+            AuthOutcome::BadCredentials,
+            AuthOutcome::NoSuchUser,
+            AuthOutcome::EmptyPassword,
+            AuthOutcome::Unreachable("timed out".to_string()),
+            AuthOutcome::ServerError("busy".to_string()),
+        ] {
+            cache.insert("ldap", "johndoe", "secret", 0, &outcome);
+            assert!(cache.get("ldap", "johndoe", "secret").is_none());
+        }
+    }
+
+    #[test]
+    fn clear_cache_forgets_every_entry() {
+        let cache = AuthCache::new(Duration::from_secs(60));
+        cache.insert("ldap", "johndoe", "secret", 0, &ok_outcome());
+        cache.insert("ldap", "janedoe", "hunter2", 0, &ok_outcome());
+        cache.clear_cache();
+        assert!(cache.get("ldap", "johndoe", "secret").is_none());
+        assert!(cache.get("ldap", "janedoe", "hunter2").is_none());
+    }
+
+    fn argv(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn config_files_fill_in_the_ldap_config() {
+        let file = parse_config_file(
+            r#"
+            servers = ["ldaps://ldap1.example.org", "ldaps://ldap2.example.org"]
+            base_dn = "dc=corp,dc=example,dc=com"
+            bind_template = "uid={},ou=people,{base_dn}"
+            scope = "one"
+            attrs = ["mail"]
+            group_naming = "all_ous"
+            operation_timeout_secs = 3
+            retry_backoff_ms = 20
+            nested_groups = 2
+            service_dn = "cn=serviceuser,dc=corp,dc=example,dc=com"
+            cache_ttl_secs = 60
+            "#,
+        )
+        .unwrap();
+        assert_eq!(file.servers.len(), 2);
+        assert_eq!(
+            file.service_dn.as_deref(),
+            Some("cn=serviceuser,dc=corp,dc=example,dc=com")
+        );
+        assert_eq!(file.cache_ttl_secs, Some(60));
+        let config = file.ldap;
+        assert_eq!(config.base_dn, "dc=corp,dc=example,dc=com");
+        assert_eq!(config.bind_dn_template, "uid={},ou=people,{base_dn}");
+        assert_eq!(config.search_scope, Scope::OneLevel);
+        assert_eq!(config.user_attrs, ["mail"]);
+        assert_eq!(config.group_naming, GroupNaming::AllOus);
+        assert_eq!(config.timeout, Duration::from_secs(3));
+        assert_eq!(config.retry_backoff, Duration::from_millis(20));
+        assert_eq!(config.nested_group_depth, 2);
+        // Left out, so the defaults
+        assert_eq!(config.page_size, 500);
+        assert_eq!(config.groups_dn(), "ou=groups,dc=corp,dc=example,dc=com");
+        assert!(config.member_search_fallback);
+    }
+
+    #[test]
+    fn config_files_are_checked_like_the_flags() {
+        for (contents, error) in [
+            ("bse_dn = \"dc=example,dc=org\"", "unknown field"),
+            ("scope = \"deep\"", "scope needs to be one of"),
+            ("servers = [\"http://ldap.example.org\"]", "not an ldap://"),
+            ("bind_template = \"uid=admin\"", "placeholder"),
+            ("page_size = 0", "page_size"),
+            ("connect_attempts = 0", "connect_attempts"),
+        ] {
+            let err = parse_config_file(contents).err().unwrap();
+            assert!(err.contains(error), "{}: {}", contents, err);
+        }
+    }
+
+    #[test]
+    fn flags_override_the_config_file() {
+        let mut file = parse_config_file(
+            r#"
+            servers = ["ldap://ldap1.example.org", "ldap://ldap2.example.org"]
+            base_dn = "dc=corp,dc=example,dc=com"
+            attrs = ["mail", "cn"]
+            follow_referrals = true
+            starttls = true
+            page_size = 100
+            concurrency = 4
+            "#,
+        )
+        .unwrap();
+        let invocation = apply_args(
+            &mut file,
+            argv(&[
+                "--server",
+                "ldap://ldap3.example.org",
+                "--attr",
+                "displayName",
+                "--page-size",
+                "50",
+                "--no-follow-referrals",
+                "--json",
+                "johndoe",
+            ]),
+        )
+        .unwrap();
+        assert_eq!(invocation.flags, ["--no-follow-referrals", "--json"]);
+        assert_eq!(invocation.args, ["johndoe"]);
+        // Lists replace the file's rather than adding to them
+        assert_eq!(file.servers, ["ldap://ldap3.example.org"]);
+        assert_eq!(file.ldap.user_attrs, ["displayName"]);
+        assert_eq!(file.ldap.page_size, 50);
+        assert!(!file.ldap.follow_referrals);
+        // Settings no flag mentioned stay as the file had them
+        assert_eq!(file.ldap.base_dn, "dc=corp,dc=example,dc=com");
+        assert!(file.starttls);
+        assert_eq!(file.concurrency, Some(4));
+
+        let mut file = parse_config_file("servers = [\"ldap://ldap1.example.org\"]").unwrap();
+        apply_args(&mut file, argv(&["--timeout", "5", "johndoe"])).unwrap();
+        assert_eq!(file.servers, ["ldap://ldap1.example.org"]);
+        assert_eq!(file.check_timeout_secs, Some(5));
+
+        let mut file = parse_config_file("starttls = true").unwrap();
+        apply_args(&mut file, argv(&["--no-starttls", "--fail-on-error"])).unwrap();
+        assert!(!file.starttls);
+    }
+
+    #[test]
+    fn bad_flag_values_are_errors() {
+        for args in [
+            &["--server", "ldap1.example.org"][..],
+            &["--page-size", "-1"],
+            &["--scope", "deep"],
+            &["--from-file"],
+            &["--fail-on-eror", "johndoe"],
+            &["--jsno"],
+            &["--no-json"],
+        ] {
+            assert!(
+                apply_args(&mut ConfigFile::default(), argv(args)).is_err(),
+                "{:?}",
+                args
+            );
+        }
+    }
+
+    #[test]
+    fn credentials_files_skip_comments_and_trim_around_the_colon() {
+        let contents = "# test accounts\n\njohndoe:secret\n  janedoe : hunter2  \n   # indented comment\nsvc:pass:with:colons\nno password here\n";
+        assert_eq!(
+            parse_credentials(contents, "users.txt"),
+            [
+                ("johndoe".to_string(), "secret".to_string()),
+                ("janedoe".to_string(), "hunter2".to_string()),
+                ("svc".to_string(), "pass:with:colons".to_string()),
+            ]
+        );
+    }
+
+    fn tag(id: u64, children: Vec<StructureTag>) -> StructureTag {
+        StructureTag {
+            class: TagClass::Application,
+            id,
+            payload: PL::C(children),
+        }
+    }
+
+    fn octets(value: &str) -> StructureTag {
+        StructureTag {
+            class: TagClass::Universal,
+            id: 4,
+            payload: PL::P(value.as_bytes().to_vec()),
+        }
+    }
+
+    #[test]
+    fn referrals_are_split_from_the_entries() {
+        let entry = tag(
+            4,
+            vec![octets("uid=johndoe,dc=example,dc=org"), tag(16, Vec::new())],
+        );
+        let reference = tag(19, vec![octets("ldap://dc2.example.org/dc=example,dc=org")]);
+        let res = LdapResult {
+            rc: 10,
+            matched: String::new(),
+            text: String::new(),
+            refs: vec!["ldaps://dc3.example.org".to_string()],
+            ctrls: Vec::new(),
+        };
+        let (entries, refs) = split_referrals(
+            vec![ResultEntry::new(entry), ResultEntry::new(reference)],
+            &res,
+        );
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].dn, "uid=johndoe,dc=example,dc=org");
+        assert_eq!(
+            refs,
+            [
+                "ldaps://dc3.example.org",
+                "ldap://dc2.example.org/dc=example,dc=org"
+            ]
+        );
+    }
+
+    #[test]
+    fn referrals_connect_as_their_scheme_says() {
+        let config = LdapConfig::default();
+        let target = |referral, mode| referral_target(referral, mode, &config).unwrap();
+        assert_eq!(
+            target(
+                "ldaps://dc2.example.org:636/ou=people,dc=example,dc=org",
+                ConnectionMode::Plain
+            ),
+            (
+                "ldaps://dc2.example.org:636".to_string(),
+                ConnectionMode::Ldaps,
+                "ou=people,dc=example,dc=org".to_string()
+            )
+        );
+        assert_eq!(
+            target("ldap://dc2.example.org", ConnectionMode::Plain),
+            (
+                "ldap://dc2.example.org".to_string(),
+                ConnectionMode::Plain,
+                "dc=example,dc=org".to_string()
+            )
+        );
+        // Never downgraded to plain text
+        for mode in [ConnectionMode::Ldaps, ConnectionMode::StartTls] {
+            assert_eq!(
+                target("ldap://dc2.example.org", mode).1,
+                ConnectionMode::StartTls
+            );
+        }
+        assert!(referral_target("http://dc2.example.org", ConnectionMode::Plain, &config).is_err());
+    }
+
+    #[test]
+    fn bind_errors_are_classified_by_result_code() {
+        assert!(matches!(
+            AuthOutcome::from_bind_error(&rejected_bind()),
+            AuthOutcome::BadCredentials
+        ));
+        assert!(matches!(
+            AuthOutcome::from_bind_error(&ldap_result(32, "no such object")),
+            AuthOutcome::NoSuchUser
+        ));
+        assert!(matches!(
+            AuthOutcome::from_bind_error(&ldap_result(53, "unwilling to perform")),
+            AuthOutcome::ServerError(_)
+        ));
+        let refused = LdapError::from(std::io::Error::from(std::io::ErrorKind::ConnectionRefused));
+        for err in [refused, LdapError::EndOfStream] {
+            assert!(matches!(
+                AuthOutcome::from_bind_error(&err),
+                AuthOutcome::Unreachable(_)
+            ));
+        }
+    }
+
+    #[test]
+    fn group_names_are_sorted_and_deduplicated() {
+        let dns = || {
+            vec![
+                "cn=vpn,ou=users,ou=groups,dc=example,dc=org".to_string(),
+                "cn=wiki,ou=admins,ou=groups,dc=example,dc=org".to_string(),
+                "cn=mail,ou=users,ou=groups,dc=example,dc=org".to_string(),
+                "cn=wiki,ou=admins,ou=groups,dc=example,dc=org".to_string(),
+            ]
+        };
+        let names = |group_naming| {
+            let config = LdapConfig {
+                group_naming,
+                ..LdapConfig::default()
+            };
+            group_names(dns(), &config)
+        };
+        assert_eq!(names(GroupNaming::FirstOu), ["admins", "users"]);
+        assert_eq!(names(GroupNaming::AllOus), ["admins", "users"]);
+        assert_eq!(
+            names(GroupNaming::FullDn),
+            [
+                "cn=mail,ou=users,ou=groups,dc=example,dc=org",
+                "cn=vpn,ou=users,ou=groups,dc=example,dc=org",
+                "cn=wiki,ou=admins,ou=groups,dc=example,dc=org",
+            ]
+        );
+    }
 }
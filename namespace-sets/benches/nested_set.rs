@@ -0,0 +1,46 @@
+// How the nested-set table scales with the size of the tree:
+//
+//     cargo bench --bench nested_set
+//
+// Each new category goes under n1, the first child of the root, so both shifting
+// UPDATEs in `add_category` renumber nearly every row. Inserts run on one connection
+// through `NestedSetStore`, the same code as `add_category` minus the pool checkout, and
+// are undone between iterations so the tree keeps its size.
+
+mod common;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+
+use namespace_sets::{find_descendants, undo_last_on, NestedSetStore, TreeStore};
+
+const SIZES: [usize; 3] = [100, 1_000, 10_000];
+
+fn nested_set(c: &mut Criterion) {
+    let (mut conn, _container) = common::connect();
+
+    let mut group = c.benchmark_group("nested_set");
+    // A 10k-node insert takes long enough that the default 100 samples would drag on
+    group.sample_size(10);
+    for size in SIZES {
+        common::build_nested_set(&mut conn, size);
+
+        group.bench_function(BenchmarkId::new("add_category", size), |b| {
+            b.iter_custom(|iters| {
+                common::time_inserts(
+                    &mut conn,
+                    iters,
+                    |conn| NestedSetStore.add_category(conn, "n1", "new"),
+                    |conn| undo_last_on(conn).map(|_| ()),
+                )
+            })
+        });
+
+        group.bench_function(BenchmarkId::new("find_descendants", size), |b| {
+            b.iter(|| find_descendants(&mut conn, "n0", false).expect("find descendants"))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, nested_set);
+criterion_main!(benches);
@@ -0,0 +1,51 @@
+// Insert cost of the two storage strategies on the same 1k-node tree:
+//
+//     cargo bench --bench tree_store
+//
+// The new category goes under n1, the first child of the root, so the nested-set
+// insert has to shift nearly every row while the ltree insert touches none.
+
+mod common;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use diesel::prelude::*;
+
+use namespace_sets::{undo_last_on, LtreeStore, NestedSetStore, TreeStore};
+
+const SIZE: usize = 1000;
+
+fn add_category(c: &mut Criterion) {
+    let (mut conn, _container) = common::connect();
+    common::build_nested_set(&mut conn, SIZE);
+    common::build_with(&LtreeStore, &mut conn, SIZE);
+
+    let mut group = c.benchmark_group("add_category");
+    group.bench_function(BenchmarkId::new("nested_set", SIZE), |b| {
+        b.iter_custom(|iters| {
+            common::time_inserts(
+                &mut conn,
+                iters,
+                |conn| NestedSetStore.add_category(conn, "n1", "new"),
+                |conn| undo_last_on(conn).map(|_| ()),
+            )
+        })
+    });
+    group.bench_function(BenchmarkId::new("ltree", SIZE), |b| {
+        b.iter_custom(|iters| {
+            common::time_inserts(
+                &mut conn,
+                iters,
+                |conn| LtreeStore.add_category(conn, "n1", "new"),
+                |conn| {
+                    diesel::sql_query("DELETE FROM ltree_category WHERE name = 'new'")
+                        .execute(conn)?;
+                    Ok(())
+                },
+            )
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, add_category);
+criterion_main!(benches);
@@ -0,0 +1,8 @@
+alter table nested_category add column depth int not null default 0;
+
+update nested_category as node
+set depth = (
+    select count(*)
+    from nested_category as ancestor
+    where ancestor.lft < node.lft and ancestor.rgt > node.rgt
+);
@@ -0,0 +1 @@
+alter table nested_category drop column active;
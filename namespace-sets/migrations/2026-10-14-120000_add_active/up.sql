@@ -0,0 +1 @@
+alter table nested_category add column active boolean not null default true;
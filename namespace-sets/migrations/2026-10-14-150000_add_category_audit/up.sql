@@ -0,0 +1,9 @@
+-- One row per add, delete or move, with the steps that reverse it
+create table category_audit (
+    id serial primary key,
+    operation varchar(16) not null,
+    category_id int not null,
+    category varchar(255) not null,
+    inverse jsonb not null,
+    created_at timestamptz not null default now()
+);
@@ -0,0 +1 @@
+drop table ltree_category;
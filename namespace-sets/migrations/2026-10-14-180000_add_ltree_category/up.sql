@@ -0,0 +1,11 @@
+-- The same forest as nested_category, stored as materialized paths for `LtreeStore`.
+-- Labels are ids, since ltree labels can't hold every category name.
+create extension if not exists ltree;
+
+create table ltree_category (
+    id serial primary key,
+    name varchar(255) not null unique,
+    path ltree not null
+);
+
+create index ltree_category_path on ltree_category using gist (path);
@@ -0,0 +1 @@
+drop index nested_category_name;
@@ -0,0 +1,2 @@
+-- Names identify categories, so two concurrent inserts must not both get one in
+create unique index nested_category_name on nested_category (name);
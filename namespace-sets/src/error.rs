@@ -0,0 +1,101 @@
+use diesel::r2d2::PoolError;
+use diesel::ConnectionError;
+use std::fmt;
+
+#[derive(Debug)]
+pub enum CategoryError {
+    Connection(ConnectionError),
+    Pool(PoolError),
+    Db(diesel::result::Error),
+    // No category with this name
+    NotFound(String),
+    // Names are unique across the whole forest
+    DuplicateName(String),
+    // A case-insensitive lookup matched more than one category
+    AmbiguousName(String),
+    CannotDeleteRoot(String),
+    WouldCreateCycle { node: String, new_parent: String },
+    NotAdjacentSiblings(String, String),
+    // Reactivating a category whose parent is still deactivated
+    InactiveParent(String),
+    // The audit log is empty
+    NothingToUndo,
+    // The category of the latest audit entry has moved since, by a change the log missed
+    UndoConflict(String),
+    // Parent-id input that can't be turned into a forest
+    InvalidAdjacency(String),
+    Json(serde_json::Error),
+}
+
+impl fmt::Display for CategoryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CategoryError::Connection(err) => write!(f, "Connection error: {}", err),
+            CategoryError::Pool(err) => write!(f, "Connection pool error: {}", err),
+            CategoryError::Db(err) => write!(f, "Database error: {}", err),
+            CategoryError::NotFound(name) => write!(f, "Category '{}' not found", name),
+            CategoryError::DuplicateName(name) => write!(f, "Category '{}' already exists", name),
+            CategoryError::AmbiguousName(name) => write!(
+                f,
+                "Category name '{}' matches more than one category when ignoring case",
+                name
+            ),
+            CategoryError::CannotDeleteRoot(name) => {
+                write!(f, "Refusing to delete root category '{}'", name)
+            }
+            CategoryError::WouldCreateCycle { node, new_parent } => write!(
+                f,
+                "Moving '{}' under '{}' would create a cycle",
+                node, new_parent
+            ),
+            CategoryError::NotAdjacentSiblings(a, b) => {
+                write!(f, "'{}' and '{}' are not adjacent siblings", a, b)
+            }
+            CategoryError::InactiveParent(name) => write!(
+                f,
+                "The parent of '{}' is deactivated, reactivate it first",
+                name
+            ),
+            CategoryError::NothingToUndo => write!(f, "Nothing to undo"),
+            CategoryError::UndoConflict(name) => write!(
+                f,
+                "'{}' has changed since its last audited change, refusing to undo",
+                name
+            ),
+            CategoryError::InvalidAdjacency(reason) => {
+                write!(f, "Invalid adjacency list: {}", reason)
+            }
+            CategoryError::Json(err) => write!(f, "JSON error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for CategoryError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CategoryError::Connection(err) => Some(err),
+            CategoryError::Pool(err) => Some(err),
+            CategoryError::Db(err) => Some(err),
+            CategoryError::Json(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<ConnectionError> for CategoryError {
+    fn from(err: ConnectionError) -> Self {
+        CategoryError::Connection(err)
+    }
+}
+
+impl From<PoolError> for CategoryError {
+    fn from(err: PoolError) -> Self {
+        CategoryError::Pool(err)
+    }
+}
+
+impl From<diesel::result::Error> for CategoryError {
+    fn from(err: diesel::result::Error) -> Self {
+        CategoryError::Db(err)
+    }
+}
@@ -0,0 +1,2390 @@
+use diesel::pg::{PgConnection, PgRowByRowLoadingMode};
+use diesel::prelude::*;
+use diesel::r2d2::{self, ConnectionManager, PoolError};
+use diesel::result::DatabaseErrorKind;
+
+use clap::ValueEnum;
+use petgraph::dot::Dot;
+use petgraph::graph::DiGraph;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::fs::File;
+use std::io::Write;
+use std::process::Command;
+use std::time::{Duration, Instant};
+use tracing::{debug, instrument};
+
+pub mod error;
+pub mod schema;
+pub mod store;
+
+pub use crate::error::CategoryError;
+use crate::schema::{category_audit, nested_category};
+pub use crate::store::{DefaultStore, LtreeStore, NestedSetStore, TreeStore};
+
+pub type Pool = r2d2::Pool<ConnectionManager<PgConnection>>;
+
+// Every function that changes the tree comes in two forms. `<name>_on` takes a connection
+// and joins whatever transaction the caller has open on it, so several changes can
+// commit or roll back together. `<name>` takes a `Pool` and runs the `_on` form on a
+// connection checked out for that one call. New mutations should provide both. Functions
+// that only read take just a connection.
+
+// Assuming `nested_category` is a Diesel model
+#[derive(Queryable, Serialize, Deserialize, Debug, Clone)]
+pub struct NestedCategory {
+    pub id: i32,
+    pub lft: i32,
+    pub rgt: i32,
+    pub name: String,
+    pub depth: i32,
+    // False once soft deleted, the row keeps its bounds
+    pub active: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CategoryNode {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lft: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rgt: Option<i32>,
+    #[serde(default)]
+    pub children: Vec<CategoryNode>,
+}
+
+// The closest category in `categories` strictly enclosing `child`. The child itself is
+// skipped by id, so a duplicate of its row can't be its own parent. In a corrupt set
+// several candidates may share the largest lft; the one with the smallest rgt wins, then
+// the lowest id, so the answer doesn't depend on the order of the slice.
+pub fn find_parent_from_categories(
+    categories: &[NestedCategory],
+    child: &NestedCategory,
+) -> Option<NestedCategory> {
+    categories
+        .iter()
+        .filter(|&cat| cat.id != child.id && cat.lft < child.lft && cat.rgt > child.rgt)
+        .min_by_key(|cat| (std::cmp::Reverse(cat.lft), cat.rgt, cat.id))
+        .cloned()
+}
+
+fn find_parent_from_db(
+    conn: &mut PgConnection,
+    child: &NestedCategory,
+) -> QueryResult<Option<NestedCategory>> {
+    debug!(child = %child.name, "finding parent");
+    nested_category::table
+        .filter(nested_category::lft.lt(child.lft))
+        .filter(nested_category::rgt.gt(child.rgt))
+        .order(nested_category::lft.desc())
+        .first(conn)
+        .optional()
+}
+
+fn find_by_name(conn: &mut PgConnection, name: &str) -> Result<NestedCategory, CategoryError> {
+    nested_category::table
+        .filter(nested_category::name.eq(name))
+        .first(conn)
+        .optional()?
+        .ok_or_else(|| CategoryError::NotFound(name.to_string()))
+}
+
+define_sql_function!(fn lower(x: diesel::sql_types::Text) -> diesel::sql_types::Text);
+
+// Look a category up by name, optionally ignoring case. Names are only unique as typed,
+// so a case-insensitive lookup that matches several categories is an error.
+pub fn find_category(
+    conn: &mut PgConnection,
+    name: &str,
+    ignore_case: bool,
+) -> Result<NestedCategory, CategoryError> {
+    if !ignore_case {
+        return find_by_name(conn, name);
+    }
+
+    let mut matches = nested_category::table
+        .filter(lower(nested_category::name).eq(lower(name)))
+        .limit(2)
+        .load::<NestedCategory>(conn)?;
+    match matches.len() {
+        0 => Err(CategoryError::NotFound(name.to_string())),
+        1 => Ok(matches.remove(0)),
+        _ => Err(CategoryError::AmbiguousName(name.to_string())),
+    }
+}
+
+// Categories whose name contains `pattern`, ignoring case, in lft order. `%`, `_` and `\`
+// in the pattern match themselves rather than acting as wildcards.
+pub fn search_categories(
+    conn: &mut PgConnection,
+    pattern: &str,
+) -> Result<Vec<NestedCategory>, CategoryError> {
+    let mut escaped = String::with_capacity(pattern.len() + 2);
+    escaped.push('%');
+    for c in pattern.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped.push('%');
+
+    Ok(nested_category::table
+        .filter(nested_category::name.ilike(escaped).escape('\\'))
+        .order(nested_category::lft)
+        .load::<NestedCategory>(conn)?)
+}
+
+// The immediate parent of `node_name`, or `None` for a root.
+pub fn find_parent(
+    conn: &mut PgConnection,
+    node_name: &str,
+) -> Result<Option<NestedCategory>, CategoryError> {
+    let node = find_by_name(conn, node_name)?;
+
+    Ok(find_parent_from_db(conn, &node)?)
+}
+
+pub fn build_graph(
+    connection: &mut PgConnection,
+    include_depth: bool,
+) -> Result<DiGraph<String, String>, CategoryError> {
+    let categories = nested_category::table.load::<NestedCategory>(connection)?;
+    let graph = build_graph_from(&categories);
+
+    // Node indices follow the slice, so each edge's target is `categories[index]`
+    Ok(graph.map(
+        |_, node| node.clone(),
+        |edge, _| {
+            let (_, child) = graph
+                .edge_endpoints(edge)
+                .expect("edge comes from this graph");
+            if include_depth {
+                format!("child (depth {})", categories[child.index()].depth)
+            } else {
+                "child".to_string()
+            }
+        },
+    ))
+}
+
+// Graph of an already loaded set of categories, connecting each to its closest enclosing
+// category in the slice. Nodes are added in slice order, and a category whose parent
+// isn't in the slice becomes a root.
+pub fn build_graph_from(categories: &[NestedCategory]) -> DiGraph<String, ()> {
+    let mut graph = DiGraph::new();
+    let mut node_indices = HashMap::new();
+
+    for cat in categories {
+        let node_format = format!("{} [ {},{} ]", cat.name, cat.lft, cat.rgt);
+        let index = graph.add_node(node_format);
+        node_indices.insert(cat.id, index);
+    }
+
+    for cat in categories {
+        if let Some(parent) = find_parent_from_categories(categories, cat) {
+            if let Some(parent_index) = node_indices.get(&parent.id) {
+                let child_index = node_indices.get(&cat.id).unwrap();
+                graph.add_edge(*parent_index, *child_index, ());
+            }
+        }
+    }
+
+    graph
+}
+
+// Graph of `node_name` and its descendants only, for a focused diagram of one branch.
+// Edges are labelled "child" as in `build_graph`.
+pub fn build_subtree_graph(
+    conn: &mut PgConnection,
+    node_name: &str,
+) -> Result<DiGraph<String, String>, CategoryError> {
+    let node = find_by_name(conn, node_name)?;
+    let categories = nested_category::table
+        .filter(nested_category::lft.ge(node.lft))
+        .filter(nested_category::rgt.le(node.rgt))
+        .order(nested_category::lft)
+        .load::<NestedCategory>(conn)?;
+
+    let mut graph = DiGraph::new();
+    // Open ancestors as (rgt, index); in lft order the top of the stack is the parent.
+    let mut stack = Vec::new();
+    for cat in &categories {
+        let index = graph.add_node(format!("{} [ {},{} ]", cat.name, cat.lft, cat.rgt));
+        while stack.last().is_some_and(|&(rgt, _)| rgt < cat.lft) {
+            stack.pop();
+        }
+        if let Some(&(_, parent_index)) = stack.last() {
+            graph.add_edge(parent_index, index, "child".to_string());
+        }
+        stack.push((cat.rgt, index));
+    }
+
+    Ok(graph)
+}
+
+pub fn graph_to_dot<E: fmt::Debug>(graph: &DiGraph<String, E>) -> String {
+    format!("{:?}", Dot::new(graph))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    Png,
+    Svg,
+    Pdf,
+    Dot,
+}
+
+impl OutputFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Png => "png",
+            OutputFormat::Svg => "svg",
+            OutputFormat::Pdf => "pdf",
+            OutputFormat::Dot => "dot",
+        }
+    }
+}
+
+pub fn export_to_png<E: fmt::Debug>(
+    graph: DiGraph<String, E>,
+    filename: &str,
+) -> Result<(), std::io::Error> {
+    export_to(graph, filename, OutputFormat::Png)
+}
+
+pub fn export_to<E: fmt::Debug>(
+    graph: DiGraph<String, E>,
+    filename: &str,
+    format: OutputFormat,
+) -> Result<(), std::io::Error> {
+    render_dot(&graph_to_dot(&graph), filename, format)
+}
+
+// Like `graph_to_dot`, but the categories named in `highlight` are filled in yellow.
+pub fn graph_to_dot_highlighted<E: fmt::Debug>(
+    graph: &DiGraph<String, E>,
+    highlight: &[&str],
+) -> String {
+    let edge_attributes = |_, _| String::new();
+    let node_attributes = |_, (_, label): (_, &String)| {
+        // Labels look like "name [ lft,rgt ]"
+        let name = label
+            .rsplit_once(" [ ")
+            .map_or(label.as_str(), |(name, _)| name);
+        if highlight.contains(&name) {
+            "style = filled, fillcolor = yellow".to_string()
+        } else {
+            String::new()
+        }
+    };
+    format!(
+        "{:?}",
+        Dot::with_attr_getters(graph, &[], &edge_attributes, &node_attributes)
+    )
+}
+
+pub fn export_to_png_highlighted<E: fmt::Debug>(
+    graph: DiGraph<String, E>,
+    filename: &str,
+    highlight: &[&str],
+) -> Result<(), std::io::Error> {
+    export_to_highlighted(graph, filename, OutputFormat::Png, highlight)
+}
+
+pub fn export_to_highlighted<E: fmt::Debug>(
+    graph: DiGraph<String, E>,
+    filename: &str,
+    format: OutputFormat,
+    highlight: &[&str],
+) -> Result<(), std::io::Error> {
+    render_dot(
+        &graph_to_dot_highlighted(&graph, highlight),
+        filename,
+        format,
+    )
+}
+
+// Write `filename.dot` and, unless only DOT was asked for, render it with graphviz.
+fn render_dot(dot: &str, filename: &str, format: OutputFormat) -> Result<(), std::io::Error> {
+    let mut dot_file = File::create(format!("{}.dot", filename))?;
+    dot_file.write_all(dot.as_bytes())?;
+
+    if format == OutputFormat::Dot {
+        return Ok(());
+    }
+
+    let output = Command::new("dot")
+        .args([
+            &format!("-T{}", format.extension()),
+            &format!("{}.dot", filename),
+            "-o",
+            &format!("{}.{}", filename, format.extension()),
+        ])
+        .output()
+        .map_err(|err| {
+            std::io::Error::new(
+                err.kind(),
+                format!("graphviz `dot` not found or failed: {}", err),
+            )
+        })?;
+
+    if !output.status.success() {
+        return Err(std::io::Error::other(format!(
+            "graphviz `dot` not found or failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    Ok(())
+}
+
+// Nest categories by containment. Expects the categories to be ordered by lft.
+fn build_category_nodes(categories: &[NestedCategory], include_bounds: bool) -> Vec<CategoryNode> {
+    let mut roots = Vec::new();
+    let mut stack: Vec<(i32, CategoryNode)> = Vec::new();
+
+    let close = |stack: &mut Vec<(i32, CategoryNode)>, roots: &mut Vec<CategoryNode>| {
+        let (_, node) = stack.pop().unwrap();
+        match stack.last_mut() {
+            Some((_, parent)) => parent.children.push(node),
+            None => roots.push(node),
+        }
+    };
+
+    for cat in categories {
+        while stack.last().is_some_and(|(rgt, _)| *rgt < cat.lft) {
+            close(&mut stack, &mut roots);
+        }
+
+        let node = CategoryNode {
+            name: cat.name.clone(),
+            lft: include_bounds.then_some(cat.lft),
+            rgt: include_bounds.then_some(cat.rgt),
+            children: Vec::new(),
+        };
+        stack.push((cat.rgt, node));
+    }
+
+    while !stack.is_empty() {
+        close(&mut stack, &mut roots);
+    }
+
+    roots
+}
+
+// A `graph TD` block for pasting into Markdown. Nodes are keyed by id and labelled with
+// the name, so names with spaces or punctuation still render.
+pub fn export_to_mermaid(conn: &mut PgConnection) -> Result<String, CategoryError> {
+    let mut mermaid = String::from("graph TD\n");
+    for (id, parent_id, name) in to_adjacency_list(conn)? {
+        mermaid.push_str(&format!(
+            "    n{}[\"{}\"]\n",
+            id,
+            name.replace('"', "#quot;")
+        ));
+        if let Some(parent_id) = parent_id {
+            mermaid.push_str(&format!("    n{} --> n{}\n", parent_id, id));
+        }
+    }
+
+    Ok(mermaid)
+}
+
+// The forest as indented text in the style of tree(1), one root after the other. In lft
+// order the open spans on the stack are the node's ancestors, and a node is the last
+// child when the next row past its subtree starts outside its parent.
+pub fn tree_to_string(conn: &mut PgConnection) -> Result<String, CategoryError> {
+    let categories = nested_category::table
+        .order(nested_category::lft)
+        .load::<NestedCategory>(conn)?;
+
+    // (rgt, is last child) per open ancestor
+    let mut open: Vec<(i32, bool)> = Vec::new();
+    let mut tree = String::new();
+    for category in &categories {
+        while open.last().is_some_and(|&(rgt, _)| rgt < category.lft) {
+            open.pop();
+        }
+        // Not `rgt + 1 == parent rgt`, the parent may have room left by a gapped insert
+        let next = categories.partition_point(|other| other.lft <= category.rgt);
+        let last = open
+            .last()
+            .is_some_and(|&(rgt, _)| categories.get(next).is_none_or(|sibling| sibling.lft > rgt));
+        // The root itself takes no column, so its children start at the margin
+        for &(_, ancestor_last) in open.iter().skip(1) {
+            tree.push_str(if ancestor_last { "    " } else { "│   " });
+        }
+        if !open.is_empty() {
+            tree.push_str(if last { "└── " } else { "├── " });
+        }
+        tree.push_str(&category.name);
+        tree.push('\n');
+        open.push((category.rgt, last));
+    }
+
+    Ok(tree)
+}
+
+pub fn print_tree(conn: &mut PgConnection) -> Result<(), CategoryError> {
+    print!("{}", tree_to_string(conn)?);
+    Ok(())
+}
+
+pub fn export_to_json(
+    conn: &mut PgConnection,
+    include_bounds: bool,
+) -> Result<String, CategoryError> {
+    let categories = nested_category::table
+        .order(nested_category::lft)
+        .load::<NestedCategory>(conn)?;
+
+    let roots = build_category_nodes(&categories, include_bounds);
+    serde_json::to_string_pretty(&roots).map_err(CategoryError::Json)
+}
+
+pub fn get_category_by_id(
+    conn: &mut PgConnection,
+    id: i32,
+) -> Result<Option<NestedCategory>, CategoryError> {
+    Ok(nested_category::table
+        .find(id)
+        .first::<NestedCategory>(conn)
+        .optional()?)
+}
+
+pub fn find_ancestors(
+    conn: &mut PgConnection,
+    node_name: &str,
+) -> Result<Vec<NestedCategory>, CategoryError> {
+    let node = find_by_name(conn, node_name)?;
+
+    Ok(nested_category::table
+        .filter(nested_category::lft.lt(node.lft))
+        .filter(nested_category::rgt.gt(node.rgt))
+        .order(nested_category::lft)
+        .load::<NestedCategory>(conn)?)
+}
+
+// Ancestors (root first) for each of `names`, in two queries whatever their number: one
+// for the named rows and one for every row that could enclose any of them. The lists are
+// then assembled in memory.
+pub fn find_ancestors_bulk(
+    conn: &mut PgConnection,
+    names: &[&str],
+) -> Result<HashMap<String, Vec<NestedCategory>>, CategoryError> {
+    let nodes = nested_category::table
+        .filter(nested_category::name.eq_any(names))
+        .load::<NestedCategory>(conn)?;
+    if let Some(missing) = names
+        .iter()
+        .find(|&&name| !nodes.iter().any(|node| node.name == name))
+    {
+        return Err(CategoryError::NotFound(missing.to_string()));
+    }
+    let (Some(max_lft), Some(min_rgt)) = (
+        nodes.iter().map(|node| node.lft).max(),
+        nodes.iter().map(|node| node.rgt).min(),
+    ) else {
+        return Ok(HashMap::new());
+    };
+
+    // Each ancestor starts before and ends after at least one node, so it starts before
+    // the last node and ends after the first one to close
+    let candidates = nested_category::table
+        .filter(nested_category::lft.lt(max_lft))
+        .filter(nested_category::rgt.gt(min_rgt))
+        .order(nested_category::lft)
+        .load::<NestedCategory>(conn)?;
+
+    Ok(nodes
+        .into_iter()
+        .map(|node| {
+            let ancestors = candidates
+                .iter()
+                .filter(|candidate| candidate.lft < node.lft && candidate.rgt > node.rgt)
+                .cloned()
+                .collect();
+            (node.name, ancestors)
+        })
+        .collect())
+}
+
+pub fn path_to_root(
+    conn: &mut PgConnection,
+    node_name: &str,
+) -> Result<Vec<String>, CategoryError> {
+    let node = find_by_name(conn, node_name)?;
+
+    Ok(nested_category::table
+        .filter(nested_category::lft.le(node.lft))
+        .filter(nested_category::rgt.ge(node.rgt))
+        .order(nested_category::lft)
+        .select(nested_category::name)
+        .load::<String>(conn)?)
+}
+
+// Bounds are inclusive so that when one node is an ancestor of the other, that ancestor
+// is the answer.
+pub fn lowest_common_ancestor(
+    conn: &mut PgConnection,
+    a: &str,
+    b: &str,
+) -> Result<Option<NestedCategory>, CategoryError> {
+    let a = find_by_name(conn, a)?;
+    let b = find_by_name(conn, b)?;
+
+    Ok(nested_category::table
+        .filter(nested_category::lft.le(a.lft.min(b.lft)))
+        .filter(nested_category::rgt.ge(a.rgt.max(b.rgt)))
+        .order(nested_category::lft.desc())
+        .first::<NestedCategory>(conn)
+        .optional()?)
+}
+
+pub fn find_descendants(
+    conn: &mut PgConnection,
+    node_name: &str,
+    include_inactive: bool,
+) -> Result<Vec<NestedCategory>, CategoryError> {
+    let node = find_by_name(conn, node_name)?;
+
+    let mut query = nested_category::table
+        .filter(nested_category::lft.gt(node.lft))
+        .filter(nested_category::rgt.lt(node.rgt))
+        .order(nested_category::lft)
+        .into_boxed();
+    if !include_inactive {
+        query = query.filter(nested_category::active);
+    }
+    Ok(query.load::<NestedCategory>(conn)?)
+}
+
+// Same rows as `find_descendants`, but streamed in row-by-row mode so a huge subtree is
+// never held in memory at once. The iterator keeps `conn` mutably borrowed until it is
+// dropped, so no other query can run on that connection meanwhile, and a pooled
+// connection has to outlive it. Stopping early is fine, dropping the iterator discards
+// the rest of the result.
+pub fn descendants_iter<'conn>(
+    conn: &'conn mut PgConnection,
+    node_name: &str,
+) -> Result<impl Iterator<Item = Result<NestedCategory, CategoryError>> + 'conn, CategoryError> {
+    let node = find_by_name(conn, node_name)?;
+
+    let rows = nested_category::table
+        .filter(nested_category::lft.gt(node.lft))
+        .filter(nested_category::rgt.lt(node.rgt))
+        .order(nested_category::lft)
+        .load_iter::<NestedCategory, PgRowByRowLoadingMode>(conn)?;
+    Ok(rows.map(|row| row.map_err(CategoryError::from)))
+}
+
+// For a valid nested set the subtree size follows directly from the bounds. That needs
+// contiguous bounds, so after `add_category_with_gap` run `compact` first or use
+// `count_descendants_with_gaps`.
+pub fn count_descendants(conn: &mut PgConnection, node_name: &str) -> Result<i64, CategoryError> {
+    let node = find_by_name(conn, node_name)?;
+
+    Ok(((node.rgt - node.lft - 1) / 2) as i64)
+}
+
+// Counts the rows instead, which stays right when gapped inserts left room in the bounds
+pub fn count_descendants_with_gaps(
+    conn: &mut PgConnection,
+    node_name: &str,
+) -> Result<i64, CategoryError> {
+    let node = find_by_name(conn, node_name)?;
+
+    Ok(nested_category::table
+        .filter(nested_category::lft.gt(node.lft))
+        .filter(nested_category::rgt.lt(node.rgt))
+        .count()
+        .get_result(conn)?)
+}
+
+// Like `count_descendants`, only exact for contiguous bounds
+pub fn is_leaf(conn: &mut PgConnection, node_name: &str) -> Result<bool, CategoryError> {
+    let node = find_by_name(conn, node_name)?;
+
+    Ok(node.rgt == node.lft + 1)
+}
+
+// A gapped leaf has room between its bounds, so this looks for rows instead
+pub fn is_leaf_with_gaps(conn: &mut PgConnection, node_name: &str) -> Result<bool, CategoryError> {
+    let node = find_by_name(conn, node_name)?;
+
+    let has_children = diesel::select(diesel::dsl::exists(
+        nested_category::table
+            .filter(nested_category::lft.gt(node.lft))
+            .filter(nested_category::lft.lt(node.rgt)),
+    ))
+    .get_result::<bool>(conn)?;
+    Ok(!has_children)
+}
+
+pub fn is_root(conn: &mut PgConnection, node_name: &str) -> Result<bool, CategoryError> {
+    let node = find_by_name(conn, node_name)?;
+
+    Ok(node.depth == 0)
+}
+
+// Strict containment, so a category is not its own ancestor.
+pub fn is_ancestor(
+    conn: &mut PgConnection,
+    ancestor: &str,
+    descendant: &str,
+) -> Result<bool, CategoryError> {
+    let ancestor = find_by_name(conn, ancestor)?;
+    let descendant = find_by_name(conn, descendant)?;
+
+    Ok(ancestor.lft < descendant.lft && ancestor.rgt > descendant.rgt)
+}
+
+pub fn find_children(
+    conn: &mut PgConnection,
+    node_name: &str,
+) -> Result<Vec<NestedCategory>, CategoryError> {
+    // Descendants come back in lft order, so a descendant is a direct child exactly when
+    // it isn't nested inside the previously found child.
+    let mut children: Vec<NestedCategory> = Vec::new();
+    for descendant in find_descendants(conn, node_name, true)? {
+        if children
+            .last()
+            .is_none_or(|child| descendant.lft > child.rgt)
+        {
+            children.push(descendant);
+        }
+    }
+
+    Ok(children)
+}
+
+// Descendants at most `max_depth` levels below `node_name`, in lft order.
+pub fn find_descendants_within(
+    conn: &mut PgConnection,
+    node_name: &str,
+    max_depth: i64,
+) -> Result<Vec<NestedCategory>, CategoryError> {
+    let node = find_by_name(conn, node_name)?;
+    let max_depth = max_depth.clamp(0, i32::MAX as i64) as i32;
+
+    Ok(nested_category::table
+        .filter(nested_category::lft.gt(node.lft))
+        .filter(nested_category::rgt.lt(node.rgt))
+        .filter(nested_category::depth.le(node.depth.saturating_add(max_depth)))
+        .order(nested_category::lft)
+        .load::<NestedCategory>(conn)?)
+}
+
+// Descendants level by level, each with its depth relative to `node_name` (children are 1).
+pub fn descendants_bfs(
+    conn: &mut PgConnection,
+    node_name: &str,
+) -> Result<Vec<(NestedCategory, i64)>, CategoryError> {
+    // In lft order the descendants' ancestors within the subtree are exactly the open
+    // spans on the stack, so the stack size is the ancestor count.
+    let mut open: Vec<i32> = Vec::new();
+    let mut levels = Vec::new();
+    for descendant in find_descendants(conn, node_name, true)? {
+        while open.last().is_some_and(|&rgt| rgt < descendant.lft) {
+            open.pop();
+        }
+        let depth = open.len() as i64 + 1;
+        open.push(descendant.rgt);
+        levels.push((descendant, depth));
+    }
+
+    levels.sort_by_key(|(category, depth)| (*depth, category.lft));
+    Ok(levels)
+}
+
+pub fn find_siblings(
+    conn: &mut PgConnection,
+    node_name: &str,
+) -> Result<Vec<NestedCategory>, CategoryError> {
+    let node = find_by_name(conn, node_name)?;
+
+    let Some(parent) = find_parent_from_db(conn, &node)? else {
+        return Ok(Vec::new());
+    };
+
+    Ok(find_children(conn, &parent.name)?
+        .into_iter()
+        .filter(|child| child.id != node.id)
+        .collect())
+}
+
+// The sibling right after `node_name` under the same parent, `None` for the last child or
+// a root (roots have no siblings, as in `find_siblings`). The first row past the subtree
+// is the next sibling if it still lies inside the parent; looking for `lft == rgt + 1`
+// would miss it after a gapped insert.
+pub fn next_sibling(
+    conn: &mut PgConnection,
+    node_name: &str,
+) -> Result<Option<NestedCategory>, CategoryError> {
+    let node = find_by_name(conn, node_name)?;
+    let Some(parent) = find_parent_from_db(conn, &node)? else {
+        return Ok(None);
+    };
+
+    Ok(nested_category::table
+        .filter(nested_category::lft.gt(node.rgt))
+        .filter(nested_category::lft.lt(parent.rgt))
+        .order(nested_category::lft)
+        .first::<NestedCategory>(conn)
+        .optional()?)
+}
+
+// The sibling right before `node_name`, `None` for the first child or a root. Of the rows
+// ending before the node, the previous sibling ends last, its own descendants end
+// earlier.
+pub fn prev_sibling(
+    conn: &mut PgConnection,
+    node_name: &str,
+) -> Result<Option<NestedCategory>, CategoryError> {
+    let node = find_by_name(conn, node_name)?;
+    let Some(parent) = find_parent_from_db(conn, &node)? else {
+        return Ok(None);
+    };
+
+    Ok(nested_category::table
+        .filter(nested_category::rgt.lt(node.lft))
+        .filter(nested_category::lft.gt(parent.lft))
+        .order(nested_category::rgt.desc())
+        .first::<NestedCategory>(conn)
+        .optional()?)
+}
+
+pub fn category_depth(conn: &mut PgConnection, node_name: &str) -> Result<i64, CategoryError> {
+    let node = find_by_name(conn, node_name)?;
+
+    Ok(nested_category::table
+        .filter(nested_category::lft.lt(node.lft))
+        .filter(nested_category::rgt.gt(node.rgt))
+        .count()
+        .get_result(conn)?)
+}
+
+pub fn check_tree_consistency(conn: &mut PgConnection) -> Result<(), Vec<String>> {
+    let categories = nested_category::table
+        .order(nested_category::lft)
+        .load::<NestedCategory>(conn)
+        .map_err(|err| vec![format!("Unable to load categories: {}", err)])?;
+
+    let mut problems = Vec::new();
+
+    for cat in &categories {
+        if cat.lft >= cat.rgt {
+            problems.push(format!(
+                "{} has lft {} not less than rgt {}",
+                cat.name, cat.lft, cat.rgt
+            ));
+        }
+    }
+
+    let mut values: Vec<i32> = categories
+        .iter()
+        .flat_map(|cat| [cat.lft, cat.rgt])
+        .collect();
+    values.sort_unstable();
+    for pair in values.windows(2) {
+        if pair[0] == pair[1] {
+            problems.push(format!("Value {} is used more than once", pair[0]));
+        }
+    }
+
+    // Gapped inserts leave holes on purpose, but `count_descendants` and `is_leaf` rely on
+    // there being none, so a gapped tree counts as a problem until `compact` has run
+    values.dedup();
+    if !values.iter().copied().eq(1..=(2 * categories.len() as i32)) {
+        problems.push(format!(
+            "Values do not form the contiguous range 1..={}, run compact after gapped inserts",
+            2 * categories.len()
+        ));
+    }
+
+    for a in &categories {
+        for b in &categories {
+            if a.lft < b.lft && b.lft < a.rgt && a.rgt < b.rgt {
+                problems.push(format!("{} and {} overlap without nesting", a.name, b.name));
+            }
+        }
+    }
+
+    // Roots and parents come from the bounds alone, since the depth column is one of the
+    // things being checked. Once the values are unique and nested, the innermost enclosing
+    // span is the one parent, and a row with none is a root.
+    for cat in &categories {
+        let enclosing = categories
+            .iter()
+            .filter(|other| other.lft < cat.lft && cat.rgt < other.rgt)
+            .count();
+        if cat.depth as usize != enclosing {
+            problems.push(format!(
+                "{} has depth {} but is nested {} levels deep",
+                cat.name, cat.depth, enclosing
+            ));
+        }
+    }
+
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(problems)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TreeStats {
+    pub node_count: i64,
+    pub root_count: i64,
+    pub leaf_count: i64,
+    // Depth of the deepest category, 0 for a forest of bare roots or an empty table
+    pub max_depth: i64,
+}
+
+impl fmt::Display for TreeStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} categories in {} trees, {} leaves, max depth {}",
+            self.node_count, self.root_count, self.leaf_count, self.max_depth
+        )
+    }
+}
+
+pub fn tree_stats(conn: &mut PgConnection) -> Result<TreeStats, CategoryError> {
+    let node_count = nested_category::table.count().get_result(conn)?;
+    let root_count = nested_category::table
+        .filter(nested_category::depth.eq(0))
+        .count()
+        .get_result(conn)?;
+    let child = diesel::alias!(nested_category as child);
+    let leaf_count = nested_category::table
+        .filter(diesel::dsl::not(diesel::dsl::exists(
+            child
+                .filter(child.field(nested_category::lft).gt(nested_category::lft))
+                .filter(child.field(nested_category::lft).lt(nested_category::rgt)),
+        )))
+        .count()
+        .get_result(conn)?;
+    let max_depth: Option<i32> = nested_category::table
+        .select(diesel::dsl::max(nested_category::depth))
+        .first(conn)?;
+
+    Ok(TreeStats {
+        node_count,
+        root_count,
+        leaf_count,
+        max_depth: max_depth.unwrap_or(0).into(),
+    })
+}
+
+// Non-root categories whose bounds fall outside every root, typically left over from
+// manual edits. `check_tree_consistency` says something is wrong, this says which rows.
+pub fn find_orphans(conn: &mut PgConnection) -> Result<Vec<NestedCategory>, CategoryError> {
+    let categories = nested_category::table
+        .order(nested_category::lft)
+        .load::<NestedCategory>(conn)?;
+    let roots: Vec<&NestedCategory> = categories.iter().filter(|cat| cat.depth == 0).collect();
+
+    Ok(categories
+        .iter()
+        .filter(|cat| {
+            cat.depth != 0
+                && !roots
+                    .iter()
+                    .any(|root| root.lft < cat.lft && cat.rgt < root.rgt)
+        })
+        .cloned()
+        .collect())
+}
+
+fn database_url() -> Result<String, ConnectionError> {
+    std::env::var("DATABASE_URL")
+        .map_err(|_| ConnectionError::InvalidConnectionUrl("DATABASE_URL must be set".into()))
+}
+
+pub fn establish_connection_with(database_url: &str) -> Result<PgConnection, ConnectionError> {
+    PgConnection::establish(database_url)
+}
+
+pub fn establish_connection() -> Result<PgConnection, ConnectionError> {
+    establish_connection_with(&database_url()?)
+}
+
+pub fn create_pool_with(database_url: &str) -> Result<Pool, PoolError> {
+    Pool::builder().build(ConnectionManager::new(database_url))
+}
+
+pub fn create_pool() -> Result<Pool, CategoryError> {
+    Ok(create_pool_with(&database_url()?)?)
+}
+
+// The queries are generated for a table called `nested_category`, so several trees in one
+// database live in separate Postgres schemas, each holding its own `nested_category`
+// (create it by running the migrations with that schema as the search path). Point a
+// connection at one of them and it sees only that tree.
+pub fn set_search_path(conn: &mut PgConnection, schema: &str) -> Result<(), CategoryError> {
+    Ok(run_set_search_path(conn, schema)?)
+}
+
+fn run_set_search_path(conn: &mut PgConnection, schema: &str) -> QueryResult<()> {
+    let quoted = schema.replace('"', "\"\"");
+    diesel::sql_query(format!("SET search_path TO \"{}\"", quoted)).execute(conn)?;
+    Ok(())
+}
+
+// Sets the search path on every connection the pool hands out.
+#[derive(Debug)]
+pub struct SearchPath(pub String);
+
+impl r2d2::CustomizeConnection<PgConnection, r2d2::Error> for SearchPath {
+    fn on_acquire(&self, conn: &mut PgConnection) -> Result<(), r2d2::Error> {
+        run_set_search_path(conn, &self.0).map_err(r2d2::Error::QueryError)
+    }
+}
+
+// A pool whose connections all work on the tree in `schema`, which needs its own
+// `nested_category` and `category_audit` tables.
+pub fn create_schema_pool_with(database_url: &str, schema: &str) -> Result<Pool, PoolError> {
+    Pool::builder()
+        .connection_customizer(Box::new(SearchPath(schema.to_string())))
+        .build(ConnectionManager::new(database_url))
+}
+
+pub fn create_schema_pool(schema: &str) -> Result<Pool, CategoryError> {
+    Ok(create_schema_pool_with(&database_url()?, schema)?)
+}
+
+// Everything is read in one `snapshot_view`, so the parts agree with each other. Not for
+// use inside a transaction, see there.
+pub fn show_category(conn: &mut PgConnection, category: &str) -> Result<(), CategoryError> {
+    println!("Category: {}", category);
+    let view = snapshot_view(conn, category)?;
+
+    println!(" {:?}", view.node);
+    println!("Ancestors of category: {}", view.node.name);
+    for ancestor in &view.ancestors {
+        println!(" {:?}", ancestor);
+    }
+    println!("Descendants of category: {}", view.node.name);
+    for descendant in &view.descendants {
+        println!(" {:?}", descendant);
+    }
+    Ok(())
+}
+
+// A category with its ancestors (root first) and descendants, as returned by
+// `snapshot_view`.
+#[derive(Debug, Clone)]
+pub struct CategoryView {
+    pub node: NestedCategory,
+    pub ancestors: Vec<NestedCategory>,
+    pub descendants: Vec<NestedCategory>,
+}
+
+// Read the node, its ancestors and its descendants in one REPEATABLE READ transaction, so
+// a concurrent writer can't shift the bounds between the three queries. Inside a
+// transaction the caller already opened the isolation level can't change any more, so
+// that fails with `AlreadyInTransaction` rather than reading without the snapshot.
+pub fn snapshot_view(
+    conn: &mut PgConnection,
+    node_name: &str,
+) -> Result<CategoryView, CategoryError> {
+    conn.build_transaction()
+        .repeatable_read()
+        .read_only()
+        .run(|conn| {
+            Ok(CategoryView {
+                node: find_by_name(conn, node_name)?,
+                ancestors: find_ancestors(conn, node_name)?,
+                descendants: find_descendants(conn, node_name, true)?,
+            })
+        })
+}
+
+pub fn show_ancestors(conn: &mut PgConnection, category: &str) -> Result<(), CategoryError> {
+    println!("Ancestors of category: {}", category);
+    for ancestor in find_ancestors(conn, category)? {
+        println!(" {:?}", ancestor);
+    }
+    Ok(())
+}
+
+pub fn show_descendants(conn: &mut PgConnection, category: &str) -> Result<(), CategoryError> {
+    println!("Descendants of category: {}", category);
+    for descendant in find_descendants(conn, category, true)? {
+        println!(" {:?}", descendant);
+    }
+    Ok(())
+}
+
+pub fn list_categories(
+    conn: &mut PgConnection,
+    include_inactive: bool,
+) -> Result<(), CategoryError> {
+    let mut query = nested_category::table.into_boxed();
+    if !include_inactive {
+        query = query.filter(nested_category::active);
+    }
+    let categories = query.load::<NestedCategory>(conn)?;
+
+    print_categories(&categories);
+    Ok(())
+}
+
+// One page of categories in lft order.
+pub fn list_categories_paged(
+    conn: &mut PgConnection,
+    offset: i64,
+    limit: i64,
+    include_inactive: bool,
+) -> Result<Vec<NestedCategory>, CategoryError> {
+    let mut query = nested_category::table
+        .order(nested_category::lft)
+        .offset(offset)
+        .limit(limit)
+        .into_boxed();
+    if !include_inactive {
+        query = query.filter(nested_category::active);
+    }
+    Ok(query.load::<NestedCategory>(conn)?)
+}
+
+pub fn print_categories(categories: &[NestedCategory]) {
+    println!("Listing categories:");
+    println!("ID  Name                 LFT RGT");
+    for category in categories {
+        println!(
+            "{:03} {:20} {:03} {:03}{}",
+            category.id,
+            category.name,
+            category.lft,
+            category.rgt,
+            if category.active { "" } else { " (inactive)" }
+        );
+    }
+}
+
+pub fn recompute_depths(pool: &Pool) -> Result<usize, CategoryError> {
+    let mut connection = pool.get()?;
+    recompute_depths_on(&mut connection)
+}
+
+// Backfill the depth column from the lft/rgt bounds.
+pub fn recompute_depths_on(conn: &mut PgConnection) -> Result<usize, CategoryError> {
+    Ok(diesel::sql_query(
+        "UPDATE nested_category AS node SET depth = (\
+            SELECT COUNT(*) FROM nested_category AS ancestor \
+            WHERE ancestor.lft < node.lft AND ancestor.rgt > node.rgt)",
+    )
+    .execute(conn)?)
+}
+
+pub fn list_categories_with_depth(conn: &mut PgConnection) -> Result<(), CategoryError> {
+    let categories = nested_category::table
+        .order(nested_category::lft)
+        .load::<NestedCategory>(conn)?;
+
+    println!("Listing categories:");
+    for category in categories {
+        println!(
+            "{}{} [ {},{} ]",
+            "  ".repeat(category.depth as usize),
+            category.name,
+            category.lft,
+            category.rgt
+        );
+    }
+    Ok(())
+}
+
+pub fn create_root_category_if_not_exists(
+    pool: &Pool,
+    name: &str,
+) -> Result<NestedCategory, CategoryError> {
+    let mut connection = pool.get()?;
+    create_root_category_if_not_exists_on(&mut connection, name)
+}
+
+pub fn create_root_category_if_not_exists_on(
+    conn: &mut PgConnection,
+    name: &str,
+) -> Result<NestedCategory, CategoryError> {
+    conn.transaction::<NestedCategory, CategoryError, _>(|connection| {
+        lock_tree(connection)?;
+        let root_category = nested_category::table
+            .filter(nested_category::name.eq(name))
+            .first::<NestedCategory>(connection)
+            .optional()?;
+
+        match root_category {
+            Some(category) => Ok(category),
+            None => insert_root(connection, name),
+        }
+    })
+}
+
+fn ensure_name_available(conn: &mut PgConnection, name: &str) -> Result<(), CategoryError> {
+    let existing = nested_category::table
+        .filter(nested_category::name.eq(name))
+        .first::<NestedCategory>(conn)
+        .optional()?;
+
+    match existing {
+        Some(_) => Err(CategoryError::DuplicateName(name.to_string())),
+        None => Ok(()),
+    }
+}
+
+// The checks above leave a window until the insert, which the unique index on name
+// closes. Postgres names the clashing value in the details, e.g. `Key (name)=(a) already
+// exists.`, which picks it out of `names` when several rows went in at once. `LtreeStore`
+// maps the unique name on its own table through here as well.
+const NAME_CONSTRAINTS: &[&str] = &["nested_category_name", "ltree_category_name_key"];
+
+pub(crate) fn duplicate_name<'a>(
+    names: &'a [&'a str],
+) -> impl FnOnce(diesel::result::Error) -> CategoryError + 'a {
+    move |err| match err {
+        diesel::result::Error::DatabaseError(DatabaseErrorKind::UniqueViolation, info)
+            if info
+                .constraint_name()
+                .is_some_and(|constraint| NAME_CONSTRAINTS.contains(&constraint)) =>
+        {
+            let details = info.details().unwrap_or_default();
+            let name = names
+                .iter()
+                .find(|name| details.contains(&format!("=({})", name)))
+                .or(names.first())
+                .map_or_else(String::new, |name| name.to_string());
+            CategoryError::DuplicateName(name)
+        }
+        err => CategoryError::Db(err),
+    }
+}
+
+// Held until the transaction ends. Every writer that reads bounds or max(rgt) and then
+// renumbers takes it first, as do the root creates for their name check. The mode
+// conflicts with itself, so a second such writer waits here instead of working from
+// numbers that are about to go stale, while plain reads carry on.
+fn lock_tree(conn: &mut PgConnection) -> QueryResult<()> {
+    diesel::sql_query("LOCK TABLE nested_category IN SHARE ROW EXCLUSIVE MODE")
+        .execute(conn)
+        .map(|_| ())
+}
+
+pub fn create_root_category(pool: &Pool, name: &str) -> Result<NestedCategory, CategoryError> {
+    let mut connection = pool.get()?;
+    create_root_category_on(&mut connection, name)
+}
+
+#[instrument(skip(conn), err(level = "info"))]
+pub fn create_root_category_on(
+    conn: &mut PgConnection,
+    name: &str,
+) -> Result<NestedCategory, CategoryError> {
+    conn.transaction::<NestedCategory, CategoryError, _>(|connection| {
+        lock_tree(connection)?;
+        ensure_name_available(connection, name)?;
+        insert_root(connection, name)
+    })
+}
+
+// Callers hold the tree lock, see `lock_tree`.
+fn insert_root(conn: &mut PgConnection, name: &str) -> Result<NestedCategory, CategoryError> {
+    // Each tree in the forest gets its own lft/rgt range after all existing ones, so
+    // containment never crosses from one tree into another.
+    let max_right = nested_category::table
+        .select(diesel::dsl::max(nested_category::rgt))
+        .first::<Option<i32>>(conn)?
+        .unwrap_or(0);
+
+    let root_category = diesel::insert_into(nested_category::table)
+        .values((
+            nested_category::name.eq(name),
+            nested_category::lft.eq(max_right + 1),
+            nested_category::rgt.eq(max_right + 2),
+            nested_category::depth.eq(0),
+        ))
+        .get_result::<NestedCategory>(conn)
+        .map_err(duplicate_name(&[name]))?;
+
+    record_audit(
+        conn,
+        "add",
+        &root_category,
+        vec![UndoStep::DeleteRange {
+            lft: root_category.lft,
+            rgt: root_category.rgt,
+        }],
+    )?;
+
+    Ok(root_category)
+}
+
+pub fn add_category(pool: &Pool, parent: &str, new: &str) -> Result<NestedCategory, CategoryError> {
+    add_category_at(pool, parent, new, None)
+}
+
+pub fn add_category_on(
+    conn: &mut PgConnection,
+    parent: &str,
+    new: &str,
+) -> Result<NestedCategory, CategoryError> {
+    add_category_at_on(conn, parent, new, None)
+}
+
+// Insert `new` as the child at index `position` among the parent's existing children,
+// shifting later siblings right. `None`, or a position past the last child, appends.
+pub fn add_category_at(
+    pool: &Pool,
+    parent: &str,
+    new: &str,
+    position: Option<usize>,
+) -> Result<NestedCategory, CategoryError> {
+    let mut connection = pool.get()?;
+    add_category_at_on(&mut connection, parent, new, position)
+}
+
+#[instrument(skip(conn), err(level = "info"))]
+pub fn add_category_at_on(
+    conn: &mut PgConnection,
+    parent: &str,
+    new: &str,
+    position: Option<usize>,
+) -> Result<NestedCategory, CategoryError> {
+    // Rows moved by the two shifting UPDATEs and how long they took, for the timing event
+    let mut shifted = (0, 0);
+    let mut shift_time = Duration::ZERO;
+    let started = Instant::now();
+    let result = conn.transaction::<NestedCategory, CategoryError, _>(|connection| {
+        lock_tree(connection)?;
+        let parent_node = find_by_name(connection, parent)?;
+
+        ensure_name_available(connection, new)?;
+
+        let sibling = match position {
+            Some(position) => find_children(connection, &parent_node.name)?
+                .into_iter()
+                .nth(position),
+            None => None,
+        };
+
+        let my_left = sibling.map_or(parent_node.rgt, |sibling| sibling.lft);
+        debug!(lft = my_left, "opening a gap for the new category");
+
+        let shift_started = Instant::now();
+        shifted.0 = diesel::update(nested_category::table.filter(nested_category::rgt.ge(my_left)))
+            .set(nested_category::rgt.eq(nested_category::rgt + 2))
+            .execute(connection)?;
+
+        shifted.1 = diesel::update(nested_category::table.filter(nested_category::lft.ge(my_left)))
+            .set(nested_category::lft.eq(nested_category::lft + 2))
+            .execute(connection)?;
+        shift_time = shift_started.elapsed();
+
+        let new_category = diesel::insert_into(nested_category::table)
+            .values((
+                nested_category::name.eq(new),
+                nested_category::lft.eq(my_left),
+                nested_category::rgt.eq(my_left + 1),
+                nested_category::depth.eq(parent_node.depth + 1),
+            ))
+            .get_result::<NestedCategory>(connection)
+            .map_err(duplicate_name(&[new]))?;
+
+        record_audit(connection, "add", &new_category, add_inverse(my_left, 2))?;
+
+        Ok(new_category)
+    });
+
+    // Also sent for failed inserts, where the counts stay 0 if no UPDATE ran
+    debug!(
+        rgt_shifted = shifted.0,
+        lft_shifted = shifted.1,
+        shift_us = shift_time.as_micros() as u64,
+        transaction_us = started.elapsed().as_micros() as u64,
+        committed = result.is_ok(),
+        "add_category timing"
+    );
+    result
+}
+
+// Append `new` under `parent`, reserving room for `gap` future children inside it and
+// `gap` further siblings after it. Later gapped inserts under the same parent use that
+// room instead of renumbering the rest of the table, so insert-heavy loads shift rows
+// far less often. A `gap` of 0 behaves like `add_category`. The bounds are no longer
+// contiguous afterwards; `compact` closes the gaps again.
+pub fn add_category_with_gap(
+    pool: &Pool,
+    parent: &str,
+    new: &str,
+    gap: i32,
+) -> Result<NestedCategory, CategoryError> {
+    let mut connection = pool.get()?;
+    add_category_with_gap_on(&mut connection, parent, new, gap)
+}
+
+#[instrument(skip(conn), err(level = "info"))]
+pub fn add_category_with_gap_on(
+    conn: &mut PgConnection,
+    parent: &str,
+    new: &str,
+    gap: i32,
+) -> Result<NestedCategory, CategoryError> {
+    conn.transaction::<NestedCategory, CategoryError, _>(|connection| {
+        lock_tree(connection)?;
+        let parent_node = find_by_name(connection, parent)?;
+
+        ensure_name_available(connection, new)?;
+
+        let gap = gap.max(0);
+        let width = 2 * (gap + 1);
+        // The last child's rgt is the largest among the descendants
+        let last_used = nested_category::table
+            .filter(nested_category::lft.gt(parent_node.lft))
+            .filter(nested_category::rgt.lt(parent_node.rgt))
+            .select(diesel::dsl::max(nested_category::rgt))
+            .first::<Option<i32>>(connection)?
+            .unwrap_or(parent_node.lft);
+        let my_left = last_used + 1;
+
+        let mut inverse = vec![UndoStep::DeleteRange {
+            lft: my_left,
+            rgt: my_left + width - 1,
+        }];
+
+        // Free numbers run from my_left up to just before the parent's rgt
+        if parent_node.rgt - my_left < width {
+            let shift = width + 2 * gap;
+            inverse.push(UndoStep::Shift {
+                from: parent_node.rgt + shift,
+                by: -shift,
+            });
+            debug!(rgt = parent_node.rgt, shift, "growing the parent");
+
+            diesel::update(nested_category::table.filter(nested_category::rgt.ge(parent_node.rgt)))
+                .set(nested_category::rgt.eq(nested_category::rgt + shift))
+                .execute(connection)?;
+
+            diesel::update(nested_category::table.filter(nested_category::lft.gt(parent_node.rgt)))
+                .set(nested_category::lft.eq(nested_category::lft + shift))
+                .execute(connection)?;
+        }
+
+        let new_category = diesel::insert_into(nested_category::table)
+            .values((
+                nested_category::name.eq(new),
+                nested_category::lft.eq(my_left),
+                nested_category::rgt.eq(my_left + width - 1),
+                nested_category::depth.eq(parent_node.depth + 1),
+            ))
+            .get_result::<NestedCategory>(connection)
+            .map_err(duplicate_name(&[new]))?;
+
+        record_audit(connection, "add", &new_category, inverse)?;
+
+        Ok(new_category)
+    })
+}
+
+pub fn compact(pool: &Pool) -> Result<usize, CategoryError> {
+    let mut connection = pool.get()?;
+    compact_on(&mut connection)
+}
+
+// Renumber the whole forest to the contiguous range 1..=2n, removing any room left by
+// `add_category_with_gap` while keeping order and nesting. Returns how many rows had
+// their bounds changed.
+#[instrument(skip(conn), err(level = "info"))]
+pub fn compact_on(conn: &mut PgConnection) -> Result<usize, CategoryError> {
+    conn.transaction::<usize, CategoryError, _>(|connection| {
+        lock_tree(connection)?;
+        let categories = nested_category::table
+            .order(nested_category::lft)
+            .load::<NestedCategory>(connection)?;
+
+        // Walk the rows in lft order, numbering each lft on the way in and each rgt once
+        // the walk leaves the subtree.
+        let mut bounds = vec![(0, 0); categories.len()];
+        let mut open: Vec<usize> = Vec::new();
+        let mut counter = 0;
+        for (index, category) in categories.iter().enumerate() {
+            while let Some(&top) = open.last() {
+                if categories[top].rgt > category.lft {
+                    break;
+                }
+                counter += 1;
+                bounds[top].1 = counter;
+                open.pop();
+            }
+            counter += 1;
+            bounds[index].0 = counter;
+            open.push(index);
+        }
+        while let Some(top) = open.pop() {
+            counter += 1;
+            bounds[top].1 = counter;
+        }
+
+        let mut renumbered = 0;
+        for (category, (lft, rgt)) in categories.iter().zip(bounds) {
+            if (category.lft, category.rgt) == (lft, rgt) {
+                continue;
+            }
+            diesel::update(nested_category::table.find(category.id))
+                .set((nested_category::lft.eq(lft), nested_category::rgt.eq(rgt)))
+                .execute(connection)?;
+            renumbered += 1;
+        }
+        debug!(renumbered, "compacted the forest");
+        clear_audit_log(connection)?;
+
+        Ok(renumbered)
+    })
+}
+
+pub fn add_children(
+    pool: &Pool,
+    parent: &str,
+    names: &[&str],
+) -> Result<Vec<NestedCategory>, CategoryError> {
+    let mut connection = pool.get()?;
+    add_children_on(&mut connection, parent, names)
+}
+
+// Append all of `names` as children of `parent`, making room for them with a single
+// shift instead of one per child.
+#[instrument(skip(conn), err(level = "info"))]
+pub fn add_children_on(
+    conn: &mut PgConnection,
+    parent: &str,
+    names: &[&str],
+) -> Result<Vec<NestedCategory>, CategoryError> {
+    if names.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    conn.transaction::<Vec<NestedCategory>, CategoryError, _>(|connection| {
+        lock_tree(connection)?;
+        let parent_node = find_by_name(connection, parent)?;
+
+        let mut sorted = names.to_vec();
+        sorted.sort_unstable();
+        if let Some(pair) = sorted.windows(2).find(|pair| pair[0] == pair[1]) {
+            return Err(CategoryError::DuplicateName(pair[0].to_string()));
+        }
+
+        let existing = nested_category::table
+            .filter(nested_category::name.eq_any(&sorted))
+            .select(nested_category::name)
+            .first::<String>(connection)
+            .optional()?;
+        if let Some(name) = existing {
+            return Err(CategoryError::DuplicateName(name));
+        }
+
+        let my_left = parent_node.rgt;
+        let width = 2 * names.len() as i32;
+
+        diesel::update(nested_category::table.filter(nested_category::rgt.ge(my_left)))
+            .set(nested_category::rgt.eq(nested_category::rgt + width))
+            .execute(connection)?;
+
+        diesel::update(nested_category::table.filter(nested_category::lft.ge(my_left)))
+            .set(nested_category::lft.eq(nested_category::lft + width))
+            .execute(connection)?;
+
+        let rows: Vec<_> = names
+            .iter()
+            .zip((my_left..).step_by(2))
+            .map(|(name, lft)| {
+                (
+                    nested_category::name.eq(*name),
+                    nested_category::lft.eq(lft),
+                    nested_category::rgt.eq(lft + 1),
+                    nested_category::depth.eq(parent_node.depth + 1),
+                )
+            })
+            .collect();
+
+        let mut children = diesel::insert_into(nested_category::table)
+            .values(&rows)
+            .get_results::<NestedCategory>(connection)
+            .map_err(duplicate_name(names))?;
+        children.sort_by_key(|child| child.lft);
+
+        // One entry per child, so undoing them one at a time works like undoing
+        // separate appends
+        for child in &children {
+            record_audit(connection, "add", child, add_inverse(child.lft, 2))?;
+        }
+
+        Ok(children)
+    })
+}
+
+pub fn delete_category(pool: &Pool, name: &str) -> Result<usize, CategoryError> {
+    let mut connection = pool.get()?;
+    delete_category_on(&mut connection, name)
+}
+
+#[instrument(skip(conn), err(level = "info"))]
+pub fn delete_category_on(conn: &mut PgConnection, name: &str) -> Result<usize, CategoryError> {
+    conn.transaction::<usize, CategoryError, _>(|connection| {
+        lock_tree(connection)?;
+        let node = find_by_name(connection, name)?;
+
+        if node.depth == 0 {
+            return Err(CategoryError::CannotDeleteRoot(node.name));
+        }
+
+        let width = node.rgt - node.lft + 1;
+        debug!(lft = node.lft, rgt = node.rgt, "deleting subtree");
+
+        let subtree = nested_category::table
+            .filter(nested_category::lft.ge(node.lft))
+            .filter(nested_category::lft.le(node.rgt))
+            .load::<NestedCategory>(connection)?;
+
+        let deleted = diesel::delete(
+            nested_category::table
+                .filter(nested_category::lft.ge(node.lft))
+                .filter(nested_category::lft.le(node.rgt)),
+        )
+        .execute(connection)?;
+
+        diesel::update(nested_category::table.filter(nested_category::rgt.gt(node.rgt)))
+            .set(nested_category::rgt.eq(nested_category::rgt - width))
+            .execute(connection)?;
+
+        diesel::update(nested_category::table.filter(nested_category::lft.gt(node.rgt)))
+            .set(nested_category::lft.eq(nested_category::lft - width))
+            .execute(connection)?;
+
+        record_audit(
+            connection,
+            "delete",
+            &node,
+            vec![
+                UndoStep::Shift {
+                    from: node.lft,
+                    by: width,
+                },
+                UndoStep::Restore { rows: subtree },
+            ],
+        )?;
+
+        Ok(deleted)
+    })
+}
+
+// Soft delete: mark a category and everything beneath it inactive. The rows keep their
+// bounds, so the tree stays valid and the categories can be reactivated later.
+pub fn deactivate_category(pool: &Pool, name: &str) -> Result<usize, CategoryError> {
+    let mut connection = pool.get()?;
+    deactivate_category_on(&mut connection, name)
+}
+
+#[instrument(skip(conn), err(level = "info"))]
+pub fn deactivate_category_on(conn: &mut PgConnection, name: &str) -> Result<usize, CategoryError> {
+    set_subtree_active(conn, name, false)
+}
+
+// Undo `deactivate_category` for a category and everything beneath it. Refuses while the
+// parent is inactive, which would leave an active category under a deleted one.
+pub fn reactivate_category(pool: &Pool, name: &str) -> Result<usize, CategoryError> {
+    let mut connection = pool.get()?;
+    reactivate_category_on(&mut connection, name)
+}
+
+#[instrument(skip(conn), err(level = "info"))]
+pub fn reactivate_category_on(conn: &mut PgConnection, name: &str) -> Result<usize, CategoryError> {
+    set_subtree_active(conn, name, true)
+}
+
+fn set_subtree_active(
+    conn: &mut PgConnection,
+    name: &str,
+    active: bool,
+) -> Result<usize, CategoryError> {
+    conn.transaction::<usize, CategoryError, _>(|connection| {
+        let node = find_by_name(connection, name)?;
+
+        if active && find_parent_from_db(connection, &node)?.is_some_and(|parent| !parent.active) {
+            return Err(CategoryError::InactiveParent(node.name));
+        }
+
+        debug!(
+            lft = node.lft,
+            rgt = node.rgt,
+            active,
+            "changing subtree state"
+        );
+        Ok(diesel::update(
+            nested_category::table
+                .filter(nested_category::lft.ge(node.lft))
+                .filter(nested_category::rgt.le(node.rgt)),
+        )
+        .set(nested_category::active.eq(active))
+        .execute(connection)?)
+    })
+}
+
+pub fn move_subtree(pool: &Pool, node: &str, new_parent: &str) -> Result<(), CategoryError> {
+    let mut connection = pool.get()?;
+    move_subtree_on(&mut connection, node, new_parent)
+}
+
+#[instrument(skip(conn), err(level = "info"))]
+pub fn move_subtree_on(
+    conn: &mut PgConnection,
+    node: &str,
+    new_parent: &str,
+) -> Result<(), CategoryError> {
+    conn.transaction::<(), CategoryError, _>(|connection| {
+        lock_tree(connection)?;
+        let node = find_by_name(connection, node)?;
+
+        let parent = find_by_name(connection, new_parent)?;
+
+        if node.id == parent.id || is_ancestor(connection, &node.name, &parent.name)? {
+            return Err(CategoryError::WouldCreateCycle {
+                node: node.name,
+                new_parent: parent.name,
+            });
+        }
+
+        let width = node.rgt - node.lft + 1;
+        debug!(lft = node.lft, rgt = node.rgt, to = %parent.name, "moving subtree");
+
+        // Park the subtree out of the way by negating its bounds.
+        diesel::update(
+            nested_category::table
+                .filter(nested_category::lft.ge(node.lft))
+                .filter(nested_category::rgt.le(node.rgt)),
+        )
+        .set((
+            nested_category::lft.eq(nested_category::lft * -1),
+            nested_category::rgt.eq(nested_category::rgt * -1),
+        ))
+        .execute(connection)?;
+
+        // Close the gap left behind.
+        diesel::update(nested_category::table.filter(nested_category::rgt.gt(node.rgt)))
+            .set(nested_category::rgt.eq(nested_category::rgt - width))
+            .execute(connection)?;
+
+        diesel::update(nested_category::table.filter(nested_category::lft.gt(node.rgt)))
+            .set(nested_category::lft.eq(nested_category::lft - width))
+            .execute(connection)?;
+
+        // If the new parent was to the right of the subtree (moving rightward), closing
+        // the gap shifted it left. Moving leftward leaves it where it was.
+        let my_right = if parent.rgt > node.rgt {
+            parent.rgt - width
+        } else {
+            parent.rgt
+        };
+
+        // Open a gap at the end of the new parent.
+        diesel::update(nested_category::table.filter(nested_category::rgt.ge(my_right)))
+            .set(nested_category::rgt.eq(nested_category::rgt + width))
+            .execute(connection)?;
+
+        diesel::update(nested_category::table.filter(nested_category::lft.gt(my_right)))
+            .set(nested_category::lft.eq(nested_category::lft + width))
+            .execute(connection)?;
+
+        // Drop the parked subtree into the gap.
+        let offset = my_right - node.lft;
+        let depth_offset = parent.depth + 1 - node.depth;
+        diesel::update(nested_category::table.filter(nested_category::lft.lt(0)))
+            .set((
+                nested_category::lft.eq(nested_category::lft * -1 + offset),
+                nested_category::rgt.eq(nested_category::rgt * -1 + offset),
+                nested_category::depth.eq(nested_category::depth + depth_offset),
+            ))
+            .execute(connection)?;
+
+        // The same moves in reverse: park the subtree again, close its new gap, reopen
+        // the old one and drop it back in.
+        let moved = NestedCategory {
+            lft: my_right,
+            rgt: my_right + width - 1,
+            depth: node.depth + depth_offset,
+            ..node.clone()
+        };
+        record_audit(
+            connection,
+            "move",
+            &moved,
+            vec![
+                UndoStep::Park {
+                    lft: moved.lft,
+                    rgt: moved.rgt,
+                },
+                UndoStep::Shift {
+                    from: moved.rgt + 1,
+                    by: -width,
+                },
+                UndoStep::Shift {
+                    from: node.lft,
+                    by: width,
+                },
+                UndoStep::Unpark {
+                    offset: node.lft - moved.lft,
+                    depth_offset: -depth_offset,
+                },
+            ],
+        )?;
+
+        Ok(())
+    })
+}
+
+pub fn swap_siblings(pool: &Pool, a: &str, b: &str) -> Result<(), CategoryError> {
+    let mut connection = pool.get()?;
+    swap_siblings_on(&mut connection, a, b)
+}
+
+// Swap two adjacent siblings together with their subtrees, e.g. to reorder menu items.
+#[instrument(skip(conn), err(level = "info"))]
+pub fn swap_siblings_on(conn: &mut PgConnection, a: &str, b: &str) -> Result<(), CategoryError> {
+    conn.transaction::<(), CategoryError, _>(|connection| {
+        lock_tree(connection)?;
+        let a = find_by_name(connection, a)?;
+        let b = find_by_name(connection, b)?;
+        let (left, right) = if a.lft < b.lft { (a, b) } else { (b, a) };
+
+        let left_parent = find_parent_from_db(connection, &left)?.map(|parent| parent.id);
+        let right_parent = find_parent_from_db(connection, &right)?.map(|parent| parent.id);
+        // Adjacent means no sibling in between, there may still be unused numbers
+        let between = nested_category::table
+            .filter(nested_category::lft.gt(left.rgt))
+            .filter(nested_category::lft.lt(right.lft))
+            .count()
+            .get_result::<i64>(connection)?;
+        if left_parent != right_parent || between > 0 {
+            return Err(CategoryError::NotAdjacentSiblings(left.name, right.name));
+        }
+
+        // The right subtree ends up starting where the left one did and the left one
+        // ending where the right one did, keeping any gap between them in the middle.
+        let left_offset = right.rgt - left.rgt;
+        let right_offset = right.lft - left.lft;
+
+        // Park the left subtree at its new position, negated so it doesn't overlap the
+        // right one while that moves.
+        diesel::update(
+            nested_category::table
+                .filter(nested_category::lft.ge(left.lft))
+                .filter(nested_category::rgt.le(left.rgt)),
+        )
+        .set((
+            nested_category::lft.eq((nested_category::lft + left_offset) * -1),
+            nested_category::rgt.eq((nested_category::rgt + left_offset) * -1),
+        ))
+        .execute(connection)?;
+
+        diesel::update(
+            nested_category::table
+                .filter(nested_category::lft.ge(right.lft))
+                .filter(nested_category::rgt.le(right.rgt)),
+        )
+        .set((
+            nested_category::lft.eq(nested_category::lft - right_offset),
+            nested_category::rgt.eq(nested_category::rgt - right_offset),
+        ))
+        .execute(connection)?;
+
+        diesel::update(nested_category::table.filter(nested_category::lft.lt(0)))
+            .set((
+                nested_category::lft.eq(nested_category::lft * -1),
+                nested_category::rgt.eq(nested_category::rgt * -1),
+            ))
+            .execute(connection)?;
+        clear_audit_log(connection)?;
+
+        Ok(())
+    })
+}
+
+// A row a mutation would insert, change or delete, as reported by the `plan_*` functions.
+#[derive(Debug, Clone)]
+pub enum PlannedChange {
+    Insert(NestedCategory),
+    Update {
+        before: NestedCategory,
+        after: NestedCategory,
+    },
+    Delete(NestedCategory),
+}
+
+impl fmt::Display for PlannedChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PlannedChange::Insert(cat) => {
+                write!(f, "INSERT {} [ {},{} ]", cat.name, cat.lft, cat.rgt)
+            }
+            PlannedChange::Update { before, after } => {
+                write!(
+                    f,
+                    "UPDATE {} [ {},{} ] -> [ {},{} ]",
+                    before.name, before.lft, before.rgt, after.lft, after.rgt
+                )?;
+                if before.depth != after.depth {
+                    write!(f, " depth {} -> {}", before.depth, after.depth)?;
+                }
+                Ok(())
+            }
+            PlannedChange::Delete(cat) => {
+                write!(f, "DELETE {} [ {},{} ]", cat.name, cat.lft, cat.rgt)
+            }
+        }
+    }
+}
+
+// Run `mutation` in a transaction that is always rolled back, and report the rows it
+// touched. Ids handed out to inserted rows are still used up, as sequences don't roll back.
+fn plan<T>(
+    pool: &Pool,
+    mutation: impl FnOnce(&mut PgConnection) -> Result<T, CategoryError>,
+) -> Result<Vec<PlannedChange>, CategoryError> {
+    fn load_by_id(conn: &mut PgConnection) -> Result<BTreeMap<i32, NestedCategory>, CategoryError> {
+        Ok(nested_category::table
+            .load::<NestedCategory>(conn)?
+            .into_iter()
+            .map(|cat| (cat.id, cat))
+            .collect())
+    }
+
+    let mut connection = pool.get()?;
+    let mut changes = Vec::new();
+    let result = connection.transaction::<(), CategoryError, _>(|connection| {
+        let mut before = load_by_id(connection)?;
+        mutation(connection)?;
+        for (id, after) in load_by_id(connection)? {
+            match before.remove(&id) {
+                None => changes.push(PlannedChange::Insert(after)),
+                Some(before)
+                    if (before.lft, before.rgt, before.depth, &before.name)
+                        != (after.lft, after.rgt, after.depth, &after.name) =>
+                {
+                    changes.push(PlannedChange::Update { before, after })
+                }
+                Some(_) => {}
+            }
+        }
+        changes.extend(before.into_values().map(PlannedChange::Delete));
+
+        Err(CategoryError::Db(
+            diesel::result::Error::RollbackTransaction,
+        ))
+    });
+
+    match result {
+        Ok(()) | Err(CategoryError::Db(diesel::result::Error::RollbackTransaction)) => Ok(changes),
+        Err(err) => Err(err),
+    }
+}
+
+// What `add_category_at` would do, without doing it.
+pub fn plan_add_category(
+    pool: &Pool,
+    parent: &str,
+    new: &str,
+    position: Option<usize>,
+) -> Result<Vec<PlannedChange>, CategoryError> {
+    plan(pool, |conn| add_category_at_on(conn, parent, new, position))
+}
+
+// What `delete_category` would do, without doing it.
+pub fn plan_delete_category(pool: &Pool, name: &str) -> Result<Vec<PlannedChange>, CategoryError> {
+    plan(pool, |conn| delete_category_on(conn, name))
+}
+
+// What `move_subtree` would do, without doing it.
+pub fn plan_move_subtree(
+    pool: &Pool,
+    node: &str,
+    new_parent: &str,
+) -> Result<Vec<PlannedChange>, CategoryError> {
+    plan(pool, |conn| move_subtree_on(conn, node, new_parent))
+}
+
+// An add, delete or move recorded in `category_audit`, as returned by `undo_last`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditEntry {
+    pub id: i32,
+    pub operation: String,
+    pub category: String,
+}
+
+impl fmt::Display for AuditEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} of '{}'", self.operation, self.category)
+    }
+}
+
+// What `undo_last` runs to reverse an entry, stored as JSON next to it.
+#[derive(Serialize, Deserialize, Debug)]
+struct Inverse {
+    // Where the category sat right after the change, None once it was deleted. Anything
+    // else means the tree has changed since, and the steps would land in the wrong place.
+    bounds: Option<(i32, i32)>,
+    steps: Vec<UndoStep>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "step", rename_all = "snake_case")]
+enum UndoStep {
+    // Delete the rows whose lft is in the range
+    DeleteRange { lft: i32, rgt: i32 },
+    // Add `by` to every lft and rgt at or past `from`
+    Shift { from: i32, by: i32 },
+    // Negate the bounds of the rows in the range, taking them out of the way
+    Park { lft: i32, rgt: i32 },
+    // Turn the parked rows positive again, moved by the offsets
+    Unpark { offset: i32, depth_offset: i32 },
+    // Put deleted rows back as they were, ids included
+    Restore { rows: Vec<NestedCategory> },
+}
+
+// Undo for a leaf of `width` numbers inserted at `lft`.
+fn add_inverse(lft: i32, width: i32) -> Vec<UndoStep> {
+    vec![
+        UndoStep::DeleteRange {
+            lft,
+            rgt: lft + width - 1,
+        },
+        UndoStep::Shift {
+            from: lft + width,
+            by: -width,
+        },
+    ]
+}
+
+// Called from inside the mutation's transaction, so the entry commits or rolls back with
+// the change it reverses. `category` is the row as it is after the change.
+fn record_audit(
+    conn: &mut PgConnection,
+    operation: &str,
+    category: &NestedCategory,
+    steps: Vec<UndoStep>,
+) -> Result<(), CategoryError> {
+    let bounds = (operation != "delete").then_some((category.lft, category.rgt));
+    let inverse = serde_json::to_value(Inverse { bounds, steps }).map_err(CategoryError::Json)?;
+
+    diesel::insert_into(category_audit::table)
+        .values((
+            category_audit::operation.eq(operation),
+            category_audit::category_id.eq(category.id),
+            category_audit::category.eq(&category.name),
+            category_audit::inverse.eq(inverse),
+        ))
+        .execute(conn)?;
+
+    Ok(())
+}
+
+// Changes that aren't audited, like `compact` or an import, renumber rows the recorded
+// steps refer to, so they drop the log instead of leaving entries that can't apply.
+fn clear_audit_log(conn: &mut PgConnection) -> QueryResult<()> {
+    diesel::delete(category_audit::table)
+        .execute(conn)
+        .map(|_| ())
+}
+
+fn apply_undo_step(conn: &mut PgConnection, step: UndoStep) -> QueryResult<()> {
+    match step {
+        UndoStep::DeleteRange { lft, rgt } => {
+            diesel::delete(
+                nested_category::table
+                    .filter(nested_category::lft.ge(lft))
+                    .filter(nested_category::lft.le(rgt)),
+            )
+            .execute(conn)?;
+        }
+        UndoStep::Shift { from, by } => {
+            diesel::update(nested_category::table.filter(nested_category::rgt.ge(from)))
+                .set(nested_category::rgt.eq(nested_category::rgt + by))
+                .execute(conn)?;
+
+            diesel::update(nested_category::table.filter(nested_category::lft.ge(from)))
+                .set(nested_category::lft.eq(nested_category::lft + by))
+                .execute(conn)?;
+        }
+        UndoStep::Park { lft, rgt } => {
+            diesel::update(
+                nested_category::table
+                    .filter(nested_category::lft.ge(lft))
+                    .filter(nested_category::rgt.le(rgt)),
+            )
+            .set((
+                nested_category::lft.eq(nested_category::lft * -1),
+                nested_category::rgt.eq(nested_category::rgt * -1),
+            ))
+            .execute(conn)?;
+        }
+        UndoStep::Unpark {
+            offset,
+            depth_offset,
+        } => {
+            diesel::update(nested_category::table.filter(nested_category::lft.lt(0)))
+                .set((
+                    nested_category::lft.eq(nested_category::lft * -1 + offset),
+                    nested_category::rgt.eq(nested_category::rgt * -1 + offset),
+                    nested_category::depth.eq(nested_category::depth + depth_offset),
+                ))
+                .execute(conn)?;
+        }
+        UndoStep::Restore { rows } => {
+            // Postgres caps the number of bind parameters per statement, so insert in chunks.
+            for chunk in rows.chunks(1000) {
+                let values: Vec<_> = chunk
+                    .iter()
+                    .map(|cat| {
+                        (
+                            nested_category::id.eq(cat.id),
+                            nested_category::lft.eq(cat.lft),
+                            nested_category::rgt.eq(cat.rgt),
+                            nested_category::name.eq(&cat.name),
+                            nested_category::depth.eq(cat.depth),
+                            nested_category::active.eq(cat.active),
+                        )
+                    })
+                    .collect();
+
+                diesel::insert_into(nested_category::table)
+                    .values(&values)
+                    .execute(conn)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn undo_last(pool: &Pool) -> Result<AuditEntry, CategoryError> {
+    let mut connection = pool.get()?;
+    undo_last_on(&mut connection)
+}
+
+// Reverse the most recent add, delete or move and drop its entry from the log, so calling
+// it again steps further back. A deleted subtree comes back with its original ids.
+#[instrument(skip(conn), err(level = "info"))]
+pub fn undo_last_on(conn: &mut PgConnection) -> Result<AuditEntry, CategoryError> {
+    conn.transaction::<AuditEntry, CategoryError, _>(|connection| {
+        lock_tree(connection)?;
+        let (id, operation, category_id, category, inverse) = category_audit::table
+            .select((
+                category_audit::id,
+                category_audit::operation,
+                category_audit::category_id,
+                category_audit::category,
+                category_audit::inverse,
+            ))
+            .order(category_audit::id.desc())
+            .first::<(i32, String, i32, String, serde_json::Value)>(connection)
+            .optional()?
+            .ok_or(CategoryError::NothingToUndo)?;
+        let inverse: Inverse = serde_json::from_value(inverse).map_err(CategoryError::Json)?;
+
+        let bounds = nested_category::table
+            .find(category_id)
+            .select((nested_category::lft, nested_category::rgt))
+            .first::<(i32, i32)>(connection)
+            .optional()?;
+        if bounds != inverse.bounds {
+            return Err(CategoryError::UndoConflict(category));
+        }
+
+        debug!(id, %operation, %category, "undoing");
+        for step in inverse.steps {
+            apply_undo_step(connection, step)?;
+        }
+        diesel::delete(category_audit::table.find(id)).execute(connection)?;
+
+        Ok(AuditEntry {
+            id,
+            operation,
+            category,
+        })
+    })
+}
+
+pub fn rename_category(pool: &Pool, old: &str, new: &str) -> Result<NestedCategory, CategoryError> {
+    let mut connection = pool.get()?;
+    rename_category_on(&mut connection, old, new)
+}
+
+#[instrument(skip(conn), err(level = "info"))]
+pub fn rename_category_on(
+    conn: &mut PgConnection,
+    old: &str,
+    new: &str,
+) -> Result<NestedCategory, CategoryError> {
+    conn.transaction::<NestedCategory, CategoryError, _>(|connection| {
+        let node = find_by_name(connection, old)?;
+
+        ensure_name_available(connection, new)?;
+
+        let renamed =
+            diesel::update(nested_category::table.filter(nested_category::id.eq(node.id)))
+                .set(nested_category::name.eq(new))
+                .get_result::<NestedCategory>(connection)
+                .map_err(duplicate_name(&[new]))?;
+
+        Ok(renamed)
+    })
+}
+
+// Assign lft/rgt to a tree in a single pre-order walk, using and advancing `counter`.
+fn number_category_nodes(
+    node: &CategoryNode,
+    counter: &mut i32,
+    depth: i32,
+    rows: &mut Vec<(String, i32, i32, i32)>,
+) {
+    let index = rows.len();
+    rows.push((node.name.clone(), *counter, 0, depth));
+    *counter += 1;
+
+    for child in &node.children {
+        number_category_nodes(child, counter, depth + 1, rows);
+    }
+
+    rows[index].2 = *counter;
+    *counter += 1;
+}
+
+pub fn import_tree_from_json(
+    pool: &Pool,
+    json: &str,
+    parent: Option<&str>,
+) -> Result<(), CategoryError> {
+    let mut connection = pool.get()?;
+    import_tree_from_json_on(&mut connection, json, parent)
+}
+
+#[instrument(skip(conn, json), err(level = "info"))]
+pub fn import_tree_from_json_on(
+    conn: &mut PgConnection,
+    json: &str,
+    parent: Option<&str>,
+) -> Result<(), CategoryError> {
+    let roots: Vec<CategoryNode> = serde_json::from_str(json).map_err(CategoryError::Json)?;
+
+    conn.transaction::<(), CategoryError, _>(|connection| {
+        lock_tree(connection)?;
+        let (mut counter, depth) = match parent {
+            Some(parent) => {
+                let parent_node = find_by_name(connection, parent)?;
+                (parent_node.rgt, parent_node.depth + 1)
+            }
+            None => {
+                diesel::delete(nested_category::table).execute(connection)?;
+                (1, 0)
+            }
+        };
+        let my_right = counter;
+
+        let mut rows = Vec::new();
+        for root in &roots {
+            number_category_nodes(root, &mut counter, depth, &mut rows);
+        }
+        debug!(
+            rows = rows.len(),
+            lft = my_right,
+            "numbered imported categories"
+        );
+
+        let mut names: Vec<&str> = rows.iter().map(|(name, ..)| name.as_str()).collect();
+        names.sort_unstable();
+        if let Some(pair) = names.windows(2).find(|pair| pair[0] == pair[1]) {
+            return Err(CategoryError::DuplicateName(pair[0].to_string()));
+        }
+
+        let existing = nested_category::table
+            .filter(nested_category::name.eq_any(&names))
+            .select(nested_category::name)
+            .first::<String>(connection)
+            .optional()?;
+        if let Some(name) = existing {
+            return Err(CategoryError::DuplicateName(name));
+        }
+
+        // Make room for the whole import under the parent in one go.
+        let width = counter - my_right;
+        diesel::update(nested_category::table.filter(nested_category::rgt.ge(my_right)))
+            .set(nested_category::rgt.eq(nested_category::rgt + width))
+            .execute(connection)?;
+
+        diesel::update(nested_category::table.filter(nested_category::lft.gt(my_right)))
+            .set(nested_category::lft.eq(nested_category::lft + width))
+            .execute(connection)?;
+
+        clear_audit_log(connection)?;
+        insert_numbered_rows(connection, &rows)
+    })
+}
+
+// The inverse of `rebuild_from_adjacency`: (id, parent_id, name) in lft order, with
+// `None` as the parent of every root.
+pub fn to_adjacency_list(
+    conn: &mut PgConnection,
+) -> Result<Vec<(i32, Option<i32>, String)>, CategoryError> {
+    let categories = nested_category::table
+        .order(nested_category::lft)
+        .load::<NestedCategory>(conn)?;
+
+    // Open ancestors as (rgt, id); in lft order the top of the stack is the parent.
+    let mut stack: Vec<(i32, i32)> = Vec::new();
+    let mut edges = Vec::with_capacity(categories.len());
+    for cat in categories {
+        while stack.last().is_some_and(|&(rgt, _)| rgt < cat.lft) {
+            stack.pop();
+        }
+        edges.push((cat.id, stack.last().map(|&(_, id)| id), cat.name));
+        stack.push((cat.rgt, cat.id));
+    }
+
+    Ok(edges)
+}
+
+// Insert (name, lft, rgt, depth) rows as produced by the numbering helpers.
+fn insert_numbered_rows(
+    conn: &mut PgConnection,
+    rows: &[(String, i32, i32, i32)],
+) -> Result<(), CategoryError> {
+    // Postgres caps the number of bind parameters per statement, so insert in chunks.
+    for chunk in rows.chunks(1000) {
+        let values: Vec<_> = chunk
+            .iter()
+            .map(|(name, lft, rgt, depth)| {
+                (
+                    nested_category::name.eq(name),
+                    nested_category::lft.eq(lft),
+                    nested_category::rgt.eq(rgt),
+                    nested_category::depth.eq(depth),
+                )
+            })
+            .collect();
+
+        let names: Vec<&str> = chunk.iter().map(|(name, ..)| name.as_str()).collect();
+        diesel::insert_into(nested_category::table)
+            .values(&values)
+            .execute(conn)
+            .map_err(duplicate_name(&names))?;
+    }
+
+    Ok(())
+}
+
+pub fn rebuild_from_adjacency(
+    pool: &Pool,
+    edges: &[(i32, Option<i32>, String)],
+) -> Result<(), CategoryError> {
+    let mut connection = pool.get()?;
+    rebuild_from_adjacency_on(&mut connection, edges)
+}
+
+// Replace the whole table with a forest given as (id, parent_id, name) rows. Siblings keep
+// their input order; the ids only describe the structure and are not kept.
+#[instrument(skip(conn, edges), fields(edges = edges.len()), err(level = "info"))]
+pub fn rebuild_from_adjacency_on(
+    conn: &mut PgConnection,
+    edges: &[(i32, Option<i32>, String)],
+) -> Result<(), CategoryError> {
+    let mut index = HashMap::new();
+    for (position, (id, ..)) in edges.iter().enumerate() {
+        if index.insert(*id, position).is_some() {
+            return Err(CategoryError::InvalidAdjacency(format!(
+                "id {} appears more than once",
+                id
+            )));
+        }
+    }
+
+    let mut children: HashMap<Option<i32>, Vec<usize>> = HashMap::new();
+    for (position, (id, parent_id, _)) in edges.iter().enumerate() {
+        if let Some(parent_id) = parent_id {
+            if !index.contains_key(parent_id) {
+                return Err(CategoryError::InvalidAdjacency(format!(
+                    "id {} has unknown parent {}",
+                    id, parent_id
+                )));
+            }
+        }
+        children.entry(*parent_id).or_default().push(position);
+    }
+
+    enum Visit {
+        Enter(usize, i32),
+        Leave(usize),
+    }
+
+    // Walk down from the roots with an explicit stack. Nodes on a cycle, or below one,
+    // can't be reached from any root, so the walk always ends; any node it didn't reach
+    // is reported as an error afterwards.
+    let mut rows: Vec<(String, i32, i32, i32)> = Vec::with_capacity(edges.len());
+    let mut counter = 1;
+    let mut stack: Vec<Visit> = children
+        .get(&None)
+        .into_iter()
+        .flatten()
+        .rev()
+        .map(|&position| Visit::Enter(position, 0))
+        .collect();
+    let mut reached = vec![false; edges.len()];
+    while let Some(visit) = stack.pop() {
+        match visit {
+            Visit::Enter(position, depth) => {
+                reached[position] = true;
+                stack.push(Visit::Leave(rows.len()));
+                rows.push((edges[position].2.clone(), counter, 0, depth));
+                counter += 1;
+                let id = Some(edges[position].0);
+                for &child in children.get(&id).into_iter().flatten().rev() {
+                    stack.push(Visit::Enter(child, depth + 1));
+                }
+            }
+            Visit::Leave(row) => {
+                rows[row].2 = counter;
+                counter += 1;
+            }
+        }
+    }
+
+    if let Some(start) = reached.iter().position(|reached| !reached) {
+        // Follow parent pointers until one repeats to name a node that is on the cycle
+        // itself rather than merely below it.
+        let mut seen = HashSet::new();
+        let mut id = edges[start].0;
+        while seen.insert(id) {
+            id = edges[index[&id]]
+                .1
+                .expect("unreached nodes always have a parent");
+        }
+        return Err(CategoryError::InvalidAdjacency(format!(
+            "id {} is part of a cycle",
+            id
+        )));
+    }
+
+    let mut names: Vec<&str> = rows.iter().map(|(name, ..)| name.as_str()).collect();
+    names.sort_unstable();
+    if let Some(pair) = names.windows(2).find(|pair| pair[0] == pair[1]) {
+        return Err(CategoryError::DuplicateName(pair[0].to_string()));
+    }
+
+    conn.transaction::<(), CategoryError, _>(|connection| {
+        lock_tree(connection)?;
+        diesel::delete(nested_category::table).execute(connection)?;
+        clear_audit_log(connection)?;
+        insert_numbered_rows(connection, &rows)
+    })
+}
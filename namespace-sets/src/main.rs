@@ -1,251 +1,268 @@
-use diesel::pg::PgConnection;
-use diesel::prelude::*;
-use std::env;
-
-use petgraph::dot::{Config, Dot};
-use petgraph::graph::DiGraph;
-use std::fs::File;
-use std::io::Write;
-use std::process::Command;
-
-mod schema;
-
-use crate::schema::nested_category;
-
-// Assuming `nested_category` is a Diesel model
-#[derive(Queryable, Debug, Clone)]
-pub struct NestedCategory {
-    pub id: i32,
-    pub lft: i32,
-    pub rgt: i32,
-    pub name: String,
-}
-
-fn find_parent_from_categories(
-    categories: &[NestedCategory],
-    child: &NestedCategory,
-) -> Option<NestedCategory> {
-    categories
-        .iter()
-        .filter(|&cat| cat.lft < child.lft && cat.rgt > child.rgt)
-        .max_by_key(|cat| cat.lft)
-        .cloned()
+use clap::{Parser, Subcommand};
+use tracing_subscriber::EnvFilter;
+
+use namespace_sets::{
+    add_category_at, add_category_with_gap, build_graph, build_subtree_graph, compact, create_pool,
+    create_root_category, create_root_category_if_not_exists, create_schema_pool,
+    deactivate_category, delete_category, export_to_highlighted, export_to_mermaid, find_category,
+    list_categories, list_categories_paged, plan_add_category, plan_delete_category,
+    print_categories, print_tree, reactivate_category, search_categories, show_category,
+    tree_stats, undo_last, CategoryError, OutputFormat, PlannedChange,
+};
+
+#[derive(Parser)]
+#[command(version, about = "Manage a nested-set category tree")]
+struct Cli {
+    /// Match category names regardless of case
+    #[arg(long, global = true)]
+    ignore_case: bool,
+    /// Work on the tree in this Postgres schema instead of the default search path
+    #[arg(long, global = true, env = "NESTED_SCHEMA")]
+    schema: Option<String>,
+    #[command(subcommand)]
+    command: Commands,
 }
 
-#[allow(dead_code)]
-fn find_parent_from_db(
-    conn: &mut PgConnection,
-    child: &NestedCategory,
-) -> QueryResult<Option<NestedCategory>> {
-    println!("Finding parent for {:?}", child);
-    nested_category::table
-        .filter(nested_category::lft.lt(child.lft))
-        .filter(nested_category::rgt.gt(child.rgt))
-        .order(nested_category::lft.desc())
-        .first(conn)
-        .optional()
+#[derive(Subcommand)]
+enum Commands {
+    /// Add a new category under a parent category
+    Add {
+        parent: String,
+        child: String,
+        /// Zero-based position among the parent's children (defaults to last)
+        #[arg(long)]
+        position: Option<usize>,
+        /// Print the rows that would change instead of changing them
+        #[arg(long)]
+        dry_run: bool,
+        /// Leave room for this many further children and siblings, see `compact`
+        #[arg(long, conflicts_with_all = ["position", "dry_run"])]
+        gap: Option<i32>,
+    },
+    /// Show a category along with its ancestors and descendants
+    Show { name: String },
+    /// List the categories whose name contains a pattern, ignoring case
+    Search { pattern: String },
+    /// List all categories, or one page of them with --limit
+    List {
+        /// Also list deactivated categories
+        #[arg(long)]
+        include_inactive: bool,
+        /// Number of categories to skip, in lft order
+        #[arg(long, default_value_t = 0)]
+        offset: i64,
+        /// Show at most this many categories
+        #[arg(long)]
+        limit: Option<i64>,
+    },
+    /// Export the category tree as a graph to category_tree.<format>
+    Graph {
+        #[arg(long, value_enum, default_value_t = OutputFormat::Png)]
+        format: OutputFormat,
+        /// Only graph this category and its descendants
+        #[arg(long)]
+        root: Option<String>,
+        /// Fill these categories in a different color (repeatable)
+        #[arg(long)]
+        highlight: Vec<String>,
+    },
+    /// Print the category tree as indented text
+    Tree,
+    /// Print the category tree as a Mermaid diagram
+    Mermaid,
+    /// Add a new root category, starting a separate tree
+    AddRoot { name: String },
+    /// Mark a category and everything beneath it inactive, keeping the rows
+    Deactivate { name: String },
+    /// Make a deactivated category and everything beneath it active again
+    Reactivate { name: String },
+    /// Renumber the tree without the room left by `add --gap`
+    Compact,
+    /// Reverse the most recent add, delete or move
+    Undo,
+    /// Delete a category and everything beneath it
+    Delete {
+        name: String,
+        /// Print the rows that would change instead of changing them
+        #[arg(long)]
+        dry_run: bool,
+    },
 }
 
-fn build_graph(connection: &mut PgConnection) -> DiGraph<String, ()> {
-    let categories = nested_category::table
-        .load::<NestedCategory>(connection)
-        .expect("Unable to load categories");
-
-    let mut graph = DiGraph::new();
-    let mut node_indices = std::collections::HashMap::new();
-
-    for cat in categories.clone() {
-        let node_format = format!("{} [ {},{} ]", cat.name, cat.lft, cat.rgt);
-        let index = graph.add_node(node_format);
-        node_indices.insert(cat.id, index);
+fn main() {
+    // Logs go to stderr, filtered by RUST_LOG (e.g. RUST_LOG=namespace_sets=debug)
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .with_writer(std::io::stderr)
+        .init();
+    let cli = Cli::parse();
+
+    // Print errors with Display rather than the Debug output `main` would give them
+    if let Err(err) = run(cli) {
+        eprintln!("Error: {}", err);
+        std::process::exit(1);
     }
+}
 
-    for cat in categories.clone() {
-        if let Some(parent) = find_parent_from_categories(&categories, &cat) {
-            if let Some(parent_index) = node_indices.get(&parent.id) {
-                let child_index = node_indices.get(&cat.id).unwrap();
-                graph.add_edge(*parent_index, *child_index, ());
+fn run(cli: Cli) -> Result<(), Box<dyn std::error::Error>> {
+    let pool = match &cli.schema {
+        Some(schema) => create_schema_pool(schema)?,
+        None => create_pool()?,
+    };
+    create_root_category_if_not_exists(&pool, "root")?;
+
+    // Turn a name as typed into the stored one, so the library calls below can match exactly
+    let resolve = |name: String| -> Result<String, CategoryError> {
+        if !cli.ignore_case {
+            return Ok(name);
+        }
+        let mut connection = pool.get()?;
+        Ok(find_category(&mut connection, &name, true)?.name)
+    };
+
+    match cli.command {
+        Commands::Add {
+            parent,
+            child,
+            position,
+            dry_run: true,
+            gap: None,
+        } => print_plan(&plan_add_category(
+            &pool,
+            &resolve(parent)?,
+            &child,
+            position,
+        )?),
+        Commands::Add {
+            parent,
+            child,
+            position,
+            dry_run: false,
+            gap: None,
+        } => {
+            let new_category = add_category_at(&pool, &resolve(parent)?, &child, position)?;
+            println!("Added category: {:?}", new_category);
+            let mut connection = pool.get()?;
+            list_categories(&mut connection, false)?;
+        }
+        Commands::Add {
+            parent,
+            child,
+            gap: Some(gap),
+            ..
+        } => {
+            let new_category = add_category_with_gap(&pool, &resolve(parent)?, &child, gap)?;
+            println!("Added category: {:?}", new_category);
+            let mut connection = pool.get()?;
+            list_categories(&mut connection, false)?;
+        }
+        Commands::AddRoot { name } => {
+            let root_category = create_root_category(&pool, &name)?;
+            println!("Added root category: {:?}", root_category);
+            let mut connection = pool.get()?;
+            list_categories(&mut connection, false)?;
+        }
+        Commands::Show { name } => {
+            let name = resolve(name)?;
+            let mut connection = pool.get()?;
+            show_category(&mut connection, &name)?;
+        }
+        Commands::Search { pattern } => {
+            let mut connection = pool.get()?;
+            print_categories(&search_categories(&mut connection, &pattern)?);
+        }
+        Commands::List {
+            include_inactive,
+            offset: 0,
+            limit: None,
+        } => {
+            let mut connection = pool.get()?;
+            list_categories(&mut connection, include_inactive)?;
+            println!("{}", tree_stats(&mut connection)?);
+        }
+        Commands::List {
+            include_inactive,
+            offset,
+            limit,
+        } => {
+            let mut connection = pool.get()?;
+            let limit = limit.unwrap_or(i64::MAX);
+            print_categories(&list_categories_paged(
+                &mut connection,
+                offset,
+                limit,
+                include_inactive,
+            )?);
+            println!("{}", tree_stats(&mut connection)?);
+        }
+        Commands::Graph {
+            format,
+            root,
+            highlight,
+        } => {
+            let mut connection = pool.get()?;
+            let highlight: Vec<&str> = highlight.iter().map(String::as_str).collect();
+            match root {
+                Some(root) => {
+                    let graph = build_subtree_graph(&mut connection, &resolve(root)?)?;
+                    export_to_highlighted(graph, "category_tree", format, &highlight)?;
+                }
+                None => {
+                    let graph = build_graph(&mut connection, false)?;
+                    export_to_highlighted(graph, "category_tree", format, &highlight)?;
+                }
             }
         }
+        Commands::Tree => {
+            let mut connection = pool.get()?;
+            print_tree(&mut connection)?;
+        }
+        Commands::Mermaid => {
+            let mut connection = pool.get()?;
+            print!("{}", export_to_mermaid(&mut connection)?);
+        }
+        Commands::Compact => {
+            println!("Renumbered {} categories", compact(&pool)?);
+        }
+        Commands::Undo => {
+            let undone = undo_last(&pool)?;
+            println!("Undid {}", undone);
+            let mut connection = pool.get()?;
+            list_categories(&mut connection, false)?;
+        }
+        Commands::Deactivate { name } => {
+            let deactivated = deactivate_category(&pool, &resolve(name)?)?;
+            println!("Deactivated {} categories", deactivated);
+            let mut connection = pool.get()?;
+            list_categories(&mut connection, true)?;
+        }
+        Commands::Reactivate { name } => {
+            let reactivated = reactivate_category(&pool, &resolve(name)?)?;
+            println!("Reactivated {} categories", reactivated);
+            let mut connection = pool.get()?;
+            list_categories(&mut connection, true)?;
+        }
+        Commands::Delete {
+            name,
+            dry_run: true,
+        } => print_plan(&plan_delete_category(&pool, &resolve(name)?)?),
+        Commands::Delete {
+            name,
+            dry_run: false,
+        } => {
+            let deleted = delete_category(&pool, &resolve(name)?)?;
+            println!("Deleted {} categories", deleted);
+            let mut connection = pool.get()?;
+            list_categories(&mut connection, false)?;
+        }
     }
 
-    graph
-}
-
-fn export_to_png(graph: DiGraph<String, ()>, filename: &str) {
-    let dot = format!("{:?}", Dot::with_config(&graph, &[Config::EdgeNoLabel]));
-
-    let mut dot_file = File::create(format!("{}.dot", filename)).unwrap();
-    dot_file.write_all(dot.as_bytes()).unwrap();
-
-    Command::new("dot")
-        .args(&[
-            "-Tpng",
-            &format!("{}.dot", filename),
-            "-o",
-            &format!("{}.png", filename),
-        ])
-        .output()
-        .expect("failed to execute process");
-}
-
-fn find_ancestors(conn: &mut PgConnection, node_name: &str) -> QueryResult<Vec<NestedCategory>> {
-    let node = nested_category::table
-        .filter(nested_category::name.eq(node_name))
-        .first::<NestedCategory>(conn)?;
-
-    nested_category::table
-        .filter(nested_category::lft.lt(node.lft))
-        .filter(nested_category::rgt.gt(node.rgt))
-        .order(nested_category::lft)
-        .load::<NestedCategory>(conn)
-}
-
-pub fn find_descendants(
-    conn: &mut PgConnection,
-    node_name: &str,
-) -> QueryResult<Vec<NestedCategory>> {
-    let node = nested_category::table
-        .filter(nested_category::name.eq(node_name))
-        .first::<NestedCategory>(conn)?;
-
-    nested_category::table
-        .filter(nested_category::lft.gt(node.lft))
-        .filter(nested_category::rgt.lt(node.rgt))
-        .order(nested_category::lft)
-        .load::<NestedCategory>(conn)
-}
-
-fn establish_connection() -> PgConnection {
-    let database_url = std::env::var("DATABASE_URL").unwrap();
-    PgConnection::establish(&database_url).expect(&format!("Error connecting to {}", database_url))
-}
-
-pub fn show_category(category: &str) {
-    println!("Category: {}", category);
-    let mut connection = establish_connection();
-    let category = nested_category::table
-        .filter(nested_category::name.eq(category))
-        .first::<NestedCategory>(&mut connection)
-        .expect("Error loading category");
-
-    println!(" {:?}", category);
-    show_ancestors(category.name.as_str());
-    show_descendants(category.name.as_str());
-}
-
-pub fn show_ancestors(category: &str) {
-    println!("Ancestors of category: {}", category);
-    for ancestor in find_ancestors(&mut establish_connection(), category).unwrap() {
-        println!(" {:?}", ancestor);
-    }
-}
-
-pub fn show_descendants(category: &str) {
-    println!("Descendants of category: {}", category);
-    for descendant in find_descendants(&mut establish_connection(), category).unwrap() {
-        println!(" {:?}", descendant);
-    }
-}
-
-pub fn list_categories() {
-    let mut connection = establish_connection();
-    let categories = nested_category::table
-        .load::<NestedCategory>(&mut connection)
-        .expect("Unable to load categories");
-
-    println!("Listing categories:");
-    println!("ID  Name                 LFT RGT");
-    for category in categories {
-        println!(
-            "{:03} {:20} {:03} {:03}",
-            category.id, category.name, category.lft, category.rgt
-        );
-    }
-}
-
-pub fn create_root_category_if_not_exists(
-    name: &str,
-) -> Result<NestedCategory, diesel::result::Error> {
-    let mut connection = establish_connection();
-    let root_category = nested_category::table
-        .filter(nested_category::name.eq(name))
-        .first::<NestedCategory>(&mut connection);
-
-    match root_category {
-        Ok(category) => Ok(category),
-        Err(_) => create_root_category(name),
-    }
-}
-
-pub fn create_root_category(name: &str) -> Result<NestedCategory, diesel::result::Error> {
-    let mut connection = establish_connection();
-    let root_category = diesel::insert_into(nested_category::table)
-        .values((
-            nested_category::name.eq(name),
-            nested_category::lft.eq(1),
-            nested_category::rgt.eq(2),
-        ))
-        .get_result::<NestedCategory>(&mut connection)?;
-
-    Ok(root_category)
-}
-
-pub fn add_category(parent: &str, new: &str) -> Result<NestedCategory, diesel::result::Error> {
-    let mut connection = establish_connection();
-
-    connection.transaction::<NestedCategory, diesel::result::Error, _>(|connection| {
-        let parent_node: NestedCategory = nested_category::table
-            .filter(nested_category::name.eq(parent))
-            .first(connection)
-            .expect("Error loading parent node");
-
-        let my_right = parent_node.rgt;
-
-        diesel::update(nested_category::table.filter(nested_category::rgt.ge(my_right)))
-            .set(nested_category::rgt.eq(nested_category::rgt + 2))
-            .execute(connection)?;
-
-        diesel::update(nested_category::table.filter(nested_category::lft.gt(my_right)))
-            .set(nested_category::lft.eq(nested_category::lft + 2))
-            .execute(connection)?;
-
-        let new_category = diesel::insert_into(nested_category::table)
-            .values((
-                nested_category::name.eq(new),
-                nested_category::lft.eq(my_right),
-                nested_category::rgt.eq(my_right + 1),
-            ))
-            .get_result::<NestedCategory>(connection)?;
-
-        Ok(new_category)
-    })
+    Ok(())
 }
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    create_root_category_if_not_exists("root").expect("Error creating root category");
-
-    if args.len() == 2 {
-        show_category(&args[1]);
-        return;
+fn print_plan(changes: &[PlannedChange]) {
+    println!("Dry run, {} rows would change:", changes.len());
+    for change in changes {
+        println!(" {}", change);
     }
-
-    if args.len() != 3 {
-        list_categories();
-
-        let mut connection = establish_connection();
-        let graph = build_graph(&mut connection);
-        export_to_png(graph, "category_tree");
-
-        return;
-    }
-
-    let parent_category = &args[1];
-    let new_category_name = &args[2];
-
-    let new_category =
-        add_category(parent_category, new_category_name).expect("Error adding category");
-    println!("Added category: {:?}", new_category);
-    list_categories();
 }
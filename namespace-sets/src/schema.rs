@@ -1,5 +1,11 @@
 // @generated automatically by Diesel CLI.
 
+pub mod sql_types {
+    #[derive(diesel::query_builder::QueryId, Clone, diesel::sql_types::SqlType)]
+    #[diesel(postgres_type(name = "ltree"))]
+    pub struct Ltree;
+}
+
 diesel::table! {
     nested_category (id) {
         id -> Int4,
@@ -7,5 +13,34 @@ diesel::table! {
         rgt -> Int4,
         #[max_length = 255]
         name -> Varchar,
+        depth -> Int4,
+        active -> Bool,
     }
 }
+
+diesel::table! {
+    category_audit (id) {
+        id -> Int4,
+        #[max_length = 16]
+        operation -> Varchar,
+        category_id -> Int4,
+        #[max_length = 255]
+        category -> Varchar,
+        inverse -> Jsonb,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use super::sql_types::Ltree;
+
+    ltree_category (id) {
+        id -> Int4,
+        #[max_length = 255]
+        name -> Varchar,
+        path -> Ltree,
+    }
+}
+
+diesel::allow_tables_to_appear_in_same_query!(category_audit, ltree_category, nested_category,);
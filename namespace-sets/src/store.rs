@@ -0,0 +1,186 @@
+// Storage strategies for the category forest. `NestedSetStore` is the lft/rgt table the
+// rest of the crate works on; `LtreeStore` keeps a materialized path per category in
+// `ltree_category` instead, so an insert is a single row without renumbering anything.
+// The two tables are independent, a forest lives in one or the other.
+
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::sql_types::Text;
+
+use crate::{
+    add_category_on, create_root_category_on, duplicate_name, find_ancestors, find_descendants,
+    CategoryError,
+};
+
+// The operations both strategies support, by category name. Descendants come in
+// pre-order with siblings in insertion order, ancestors root first.
+pub trait TreeStore {
+    fn create_root(&self, conn: &mut PgConnection, name: &str) -> Result<(), CategoryError>;
+
+    fn add_category(
+        &self,
+        conn: &mut PgConnection,
+        parent: &str,
+        new: &str,
+    ) -> Result<(), CategoryError>;
+
+    fn find_descendants(
+        &self,
+        conn: &mut PgConnection,
+        name: &str,
+    ) -> Result<Vec<String>, CategoryError>;
+
+    fn find_ancestors(
+        &self,
+        conn: &mut PgConnection,
+        name: &str,
+    ) -> Result<Vec<String>, CategoryError>;
+}
+
+pub type DefaultStore = NestedSetStore;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NestedSetStore;
+
+impl TreeStore for NestedSetStore {
+    fn create_root(&self, conn: &mut PgConnection, name: &str) -> Result<(), CategoryError> {
+        create_root_category_on(conn, name).map(|_| ())
+    }
+
+    fn add_category(
+        &self,
+        conn: &mut PgConnection,
+        parent: &str,
+        new: &str,
+    ) -> Result<(), CategoryError> {
+        add_category_on(conn, parent, new).map(|_| ())
+    }
+
+    fn find_descendants(
+        &self,
+        conn: &mut PgConnection,
+        name: &str,
+    ) -> Result<Vec<String>, CategoryError> {
+        // ltree has no soft delete, so neither side leaves inactive categories out
+        Ok(find_descendants(conn, name, true)?
+            .into_iter()
+            .map(|category| category.name)
+            .collect())
+    }
+
+    fn find_ancestors(
+        &self,
+        conn: &mut PgConnection,
+        name: &str,
+    ) -> Result<Vec<String>, CategoryError> {
+        Ok(find_ancestors(conn, name)?
+            .into_iter()
+            .map(|category| category.name)
+            .collect())
+    }
+}
+
+// Each row's path is the ids from its root down to itself, e.g. `1.4.9`. Not covered by
+// the undo log or the consistency checks, which only know the nested-set table.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LtreeStore;
+
+#[derive(QueryableByName)]
+struct NameRow {
+    #[diesel(sql_type = Text)]
+    name: String,
+}
+
+#[derive(QueryableByName)]
+struct PathRow {
+    #[diesel(sql_type = Text)]
+    path: String,
+}
+
+impl LtreeStore {
+    fn path_of(conn: &mut PgConnection, name: &str) -> Result<String, CategoryError> {
+        diesel::sql_query("SELECT path::text AS path FROM ltree_category WHERE name = $1")
+            .bind::<Text, _>(name)
+            .get_result::<PathRow>(conn)
+            .optional()?
+            .map(|row| row.path)
+            .ok_or_else(|| CategoryError::NotFound(name.to_string()))
+    }
+}
+
+// The unique index on name stands in for `ensure_name_available`, so concurrent inserts
+// need no lock.
+impl TreeStore for LtreeStore {
+    fn create_root(&self, conn: &mut PgConnection, name: &str) -> Result<(), CategoryError> {
+        diesel::sql_query(
+            "INSERT INTO ltree_category (id, name, path) \
+             SELECT n.id, $1, text2ltree(n.id::text) \
+             FROM (SELECT nextval('ltree_category_id_seq') AS id) n",
+        )
+        .bind::<Text, _>(name)
+        .execute(conn)
+        .map_err(duplicate_name(&[name]))?;
+        Ok(())
+    }
+
+    fn add_category(
+        &self,
+        conn: &mut PgConnection,
+        parent: &str,
+        new: &str,
+    ) -> Result<(), CategoryError> {
+        let inserted = diesel::sql_query(
+            "INSERT INTO ltree_category (id, name, path) \
+             SELECT n.id, $2, p.path || text2ltree(n.id::text) \
+             FROM ltree_category p, (SELECT nextval('ltree_category_id_seq') AS id) n \
+             WHERE p.name = $1",
+        )
+        .bind::<Text, _>(parent)
+        .bind::<Text, _>(new)
+        .execute(conn)
+        .map_err(duplicate_name(&[new]))?;
+
+        match inserted {
+            0 => Err(CategoryError::NotFound(parent.to_string())),
+            _ => Ok(()),
+        }
+    }
+
+    fn find_descendants(
+        &self,
+        conn: &mut PgConnection,
+        name: &str,
+    ) -> Result<Vec<String>, CategoryError> {
+        let path = Self::path_of(conn, name)?;
+        // Comparing the labels as integers keeps siblings in id order, ltree's own
+        // ordering would compare them as text
+        Ok(diesel::sql_query(
+            "SELECT name FROM ltree_category \
+             WHERE path <@ text2ltree($1) AND path <> text2ltree($1) \
+             ORDER BY string_to_array(path::text, '.')::int[]",
+        )
+        .bind::<Text, _>(&path)
+        .load::<NameRow>(conn)?
+        .into_iter()
+        .map(|row| row.name)
+        .collect())
+    }
+
+    fn find_ancestors(
+        &self,
+        conn: &mut PgConnection,
+        name: &str,
+    ) -> Result<Vec<String>, CategoryError> {
+        let path = Self::path_of(conn, name)?;
+        Ok(diesel::sql_query(
+            "SELECT name FROM ltree_category \
+             WHERE path @> text2ltree($1) AND path <> text2ltree($1) \
+             ORDER BY nlevel(path)",
+        )
+        .bind::<Text, _>(&path)
+        .load::<NameRow>(conn)?
+        .into_iter()
+        .map(|row| row.name)
+        .collect())
+    }
+}